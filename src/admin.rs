@@ -0,0 +1,83 @@
+use crate::endpoints::{model_forbidden_response, model_not_found_response, queue_error_response};
+use crate::error::LLMError;
+use crate::fs_reading::model_file_exists;
+use crate::keyring::ApiKey;
+use crate::responses::{error_response, prompt_response};
+use crate::state::ServerState;
+use hyper::body::to_bytes;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::Deserialize;
+use std::sync::Arc;
+
+// Struct to represent reload model input
+#[derive(Deserialize, Debug, Default)]
+struct ReloadInput {
+    // The model to reload, the default model if not given
+    model: Option<String>,
+    // The model file to load, the model's current file if not given
+    path: Option<String>,
+}
+
+// Reload a model from a (possibly new) file without interrupting the server.
+// Responds once the new copy of the model has been swapped in.
+pub async fn reload_endpoint(
+    mut req: Request<Body>,
+    state: &ServerState,
+) -> Result<Response<Body>, LLMError> {
+    if req.method() != Method::POST {
+        return error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+    }
+
+    // An empty body reloads the default model from its current file
+    let body_bytes = to_bytes(req.body_mut()).await.unwrap_or_default();
+    let input: ReloadInput = if body_bytes.is_empty() {
+        ReloadInput::default()
+    } else {
+        match serde_json::from_slice(&body_bytes) {
+            Ok(input) => input,
+            Err(_) => {
+                return error_response(StatusCode::BAD_REQUEST, "Failed to parse request body")
+            }
+        }
+    };
+
+    let path = req.uri().path();
+    let model = match state.models.get(input.model.as_deref()) {
+        Some(model) => model,
+        None => return model_not_found_response(path, &input.model.unwrap_or_default()),
+    };
+    if let Some(key) = req.extensions().get::<Arc<ApiKey>>() {
+        if !key.allows_model(&model.name) {
+            return model_forbidden_response(path, &model.name);
+        }
+    }
+    if let Some(model_path) = &input.path {
+        // Loading a file the client picks is only up to keys with the admin scope,
+        // so a server without API keys only reloads models from their current files
+        let is_admin = req
+            .extensions()
+            .get::<Arc<ApiKey>>()
+            .is_some_and(|key| key.admin);
+        if !is_admin {
+            return error_response(
+                StatusCode::FORBIDDEN,
+                "Reloading a model from another file needs an API key with the admin scope",
+            );
+        }
+        if !model_file_exists(model_path) {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                "Model file could not be found/read",
+            );
+        }
+    }
+
+    match state.models.reload(model, input.path.as_deref()).await {
+        Ok(()) => prompt_response(
+            StatusCode::OK,
+            true,
+            &format!("Model `{}` reloaded from {}", model.name, model.path()),
+        ),
+        Err(error) => queue_error_response(path, error),
+    }
+}
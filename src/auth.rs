@@ -0,0 +1,94 @@
+use crate::endpoints::route_requests;
+use crate::error::LLMError;
+use crate::health::is_health_check;
+use crate::keyring::{ApiKey, Keyring};
+use crate::ratelimit::rate_limit_layer;
+use crate::state::ServerState;
+use hyper::{header, Body, Request, Response, StatusCode};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+// Realm advertised in the `WWW-Authenticate` header
+const REALM: &str = "open-llm-server";
+
+// Why a request was not let through to the endpoints
+enum AuthError {
+    // No `Authorization` header was sent
+    Missing,
+    // The key is not in the keyring
+    Invalid,
+    // The key may not use the requested endpoint
+    Forbidden(String),
+}
+
+impl AuthError {
+    // Builds the response, with the `WWW-Authenticate` challenge from RFC 6750
+    fn into_response(self) -> Result<Response<Body>, LLMError> {
+        let (status, challenge, message) = match self {
+            AuthError::Missing => (
+                StatusCode::UNAUTHORIZED,
+                format!("Bearer realm=\"{}\"", REALM),
+                "No API key provided".to_string(),
+            ),
+            AuthError::Invalid => (
+                StatusCode::UNAUTHORIZED,
+                format!("Bearer realm=\"{}\", error=\"invalid_token\"", REALM),
+                "Invalid API key".to_string(),
+            ),
+            AuthError::Forbidden(name) => (
+                StatusCode::FORBIDDEN,
+                format!("Bearer realm=\"{}\", error=\"insufficient_scope\"", REALM),
+                format!("API key `{}` may not use this endpoint", name),
+            ),
+        };
+        Ok(Response::builder()
+            .status(status)
+            .header(header::WWW_AUTHENTICATE, challenge)
+            .body(Body::from(message))?)
+    }
+}
+
+// Authenticates every request before it reaches the endpoints.
+// Only reads the keyring, which never changes while the server runs,
+// so checking a key never waits on the models or on other requests.
+pub async fn auth_layer(
+    mut req: Request<Body>,
+    state: Arc<ServerState>,
+    remote_addr: SocketAddr,
+) -> Result<Response<Body>, LLMError> {
+    // Health checks are open to probes which can't send an API key, and are never rate limited
+    if is_health_check(req.uri().path()) {
+        return route_requests(req, state).await;
+    }
+    if let Some(keyring) = &state.keyring {
+        let key = match check_api_key(&req, keyring) {
+            Ok(key) => key,
+            Err(e) => return e.into_response(),
+        };
+        // Let the endpoints check which models the key may use,
+        // and count its usage against its own limits
+        req.extensions_mut().insert(key);
+    }
+    rate_limit_layer(req, state, remote_addr).await
+}
+
+// Finds the key the request was made with, and checks that it may use the endpoint
+fn check_api_key(req: &Request<Body>, keyring: &Keyring) -> Result<Arc<ApiKey>, AuthError> {
+    // Accept both the bare key and the `Bearer <key>` scheme OpenAI clients send
+    let auth_value = match req.headers().get(header::AUTHORIZATION) {
+        Some(auth_header) => auth_header.to_str().unwrap_or_default().trim(),
+        None => return Err(AuthError::Missing),
+    };
+    let provided_key = match auth_value.split_once(' ') {
+        Some((scheme, key)) if scheme.eq_ignore_ascii_case("Bearer") => key.trim(),
+        _ => auth_value,
+    };
+
+    let key = keyring
+        .authenticate(provided_key)
+        .ok_or(AuthError::Invalid)?;
+    if !key.allows_endpoint(req.uri().path()) {
+        return Err(AuthError::Forbidden(key.name.clone()));
+    }
+    Ok(key)
+}
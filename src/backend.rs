@@ -0,0 +1,123 @@
+use crate::error::LLMError;
+use crate::llm_interface::{LLMInterface, TokenSender};
+use crate::mock::MockSettings;
+use crate::sampling::Sampling;
+use async_trait::async_trait;
+
+// What runs the models being served
+#[derive(Debug, Clone)]
+pub enum BackendKind {
+    // llama.cpp, loading GGML model files
+    Llama,
+    // A deterministic stand-in which needs no model files, for testing
+    Mock(MockSettings),
+}
+
+impl BackendKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BackendKind::Llama => "llama",
+            BackendKind::Mock(_) => "mock",
+        }
+    }
+
+    // Whether models are loaded from files, which must then exist
+    pub fn uses_model_files(&self) -> bool {
+        matches!(self, BackendKind::Llama)
+    }
+}
+
+// A loaded model, as used by the endpoints.
+// Requests get exclusive access to it through the model's queue, and run it on a blocking thread,
+// so the futures returned don't need to be `Send`.
+#[async_trait(?Send)]
+pub trait Backend: Send {
+    // The name requests select the model by
+    fn model_name(&self) -> String;
+
+    // Count the number of tokens the given text is made up of
+    fn count_tokens(&self, text: &str) -> Result<usize, LLMError>;
+
+    // The number of tokens the model's context window holds, the prompt and response together
+    fn context_size(&self) -> usize;
+
+    // Generate a response to the prompt.
+    // Fails with `LLMError::Cancelled` if the generation is cancelled while running.
+    async fn submit_prompt(
+        &mut self,
+        prompt_text: &str,
+        sampling: &Sampling,
+    ) -> Result<String, LLMError>;
+
+    // Generate a response to the prompt, sending each token through `token_tx` as it is generated.
+    // The full response is still returned once generation completes.
+    async fn submit_prompt_streaming(
+        &mut self,
+        prompt_text: &str,
+        sampling: &Sampling,
+        token_tx: TokenSender,
+    ) -> Result<String, LLMError>;
+
+    // Check that the model still generates, for the readiness probe.
+    // Leaves any cached prompt state alone, so probes don't slow down the next request.
+    fn check_generation(&mut self) -> Result<(), LLMError>;
+
+    // Whether the model has also been loaded in embedding mode, which takes as much memory again
+    fn embeddings_loaded(&self) -> bool;
+
+    // Load the model in embedding mode, which embeddings are generated with.
+    // The registry does this once it has made room in the memory budget for the second copy.
+    fn load_embeddings(&mut self) -> Result<(), LLMError>;
+
+    // Generate the embedding vector for the given input.
+    // Fails if the model has not been loaded in embedding mode.
+    async fn generate_embeddings(&mut self, input_text: &str) -> Result<Vec<f32>, LLMError>;
+}
+
+#[async_trait(?Send)]
+impl Backend for LLMInterface {
+    fn model_name(&self) -> String {
+        LLMInterface::model_name(self)
+    }
+
+    fn count_tokens(&self, text: &str) -> Result<usize, LLMError> {
+        LLMInterface::count_tokens(self, text)
+    }
+
+    fn context_size(&self) -> usize {
+        LLMInterface::context_size(self)
+    }
+
+    async fn submit_prompt(
+        &mut self,
+        prompt_text: &str,
+        sampling: &Sampling,
+    ) -> Result<String, LLMError> {
+        LLMInterface::submit_prompt(self, prompt_text, sampling).await
+    }
+
+    async fn submit_prompt_streaming(
+        &mut self,
+        prompt_text: &str,
+        sampling: &Sampling,
+        token_tx: TokenSender,
+    ) -> Result<String, LLMError> {
+        LLMInterface::submit_prompt_streaming(self, prompt_text, sampling, token_tx).await
+    }
+
+    fn check_generation(&mut self) -> Result<(), LLMError> {
+        LLMInterface::check_generation(self)
+    }
+
+    fn embeddings_loaded(&self) -> bool {
+        self.embeddings.is_some()
+    }
+
+    fn load_embeddings(&mut self) -> Result<(), LLMError> {
+        LLMInterface::load_embeddings(self)
+    }
+
+    async fn generate_embeddings(&mut self, input_text: &str) -> Result<Vec<f32>, LLMError> {
+        LLMInterface::generate_embeddings(self, input_text).await
+    }
+}
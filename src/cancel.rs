@@ -0,0 +1,147 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+thread_local! {
+    // The token of the generation currently running on this thread
+    static CURRENT_TOKEN: RefCell<Option<CancelToken>> = const { RefCell::new(None) };
+}
+
+// A flag which tells a running generation to stop as soon as possible
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    // Make this the token checked by generations running on the current thread,
+    // until the returned guard is dropped
+    pub fn install(&self) -> InstalledToken {
+        CURRENT_TOKEN.with(|current| *current.borrow_mut() = Some(self.clone()));
+        InstalledToken
+    }
+}
+
+// Whether the token installed on the current thread (if any) has been cancelled
+pub fn current_is_cancelled() -> bool {
+    CURRENT_TOKEN.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .map(|token| token.is_cancelled())
+            .unwrap_or(false)
+    })
+}
+
+// Uninstalls the current thread's token once dropped
+pub struct InstalledToken;
+
+impl Drop for InstalledToken {
+    fn drop(&mut self) {
+        CURRENT_TOKEN.with(|current| *current.borrow_mut() = None);
+    }
+}
+
+// Cancels the token if dropped before being disarmed.
+// Held by request handlers, which hyper drops when the client disconnects.
+pub struct CancelOnDrop(Option<CancelToken>);
+
+impl CancelOnDrop {
+    pub fn new(token: CancelToken) -> Self {
+        Self(Some(token))
+    }
+
+    pub fn disarm(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(token) = self.0.take() {
+            token.cancel();
+        }
+    }
+}
+
+// A generation in flight, with the name of the API key which started it (if any)
+struct Registered {
+    token: CancelToken,
+    owner: Option<String>,
+}
+
+// The generations currently in flight, by request id, so they can be cancelled
+#[derive(Default)]
+pub struct Generations {
+    tokens: Arc<Mutex<HashMap<String, Registered>>>,
+}
+
+impl Generations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Register a new generation, which stays cancellable until the returned handle is dropped.
+    // Returns None if a generation with the same request id is already in flight.
+    pub fn register(&self, request_id: &str, owner: Option<&str>) -> Option<Generation> {
+        let mut tokens = self.tokens.lock().unwrap();
+        if tokens.contains_key(request_id) {
+            return None;
+        }
+        let token = CancelToken::new();
+        tokens.insert(
+            request_id.to_string(),
+            Registered {
+                token: token.clone(),
+                owner: owner.map(|owner| owner.to_string()),
+            },
+        );
+        Some(Generation {
+            request_id: request_id.to_string(),
+            token,
+            tokens: Arc::clone(&self.tokens),
+        })
+    }
+
+    // Cancel the generation with the given request id, returns false if there is none
+    // or it was started with another API key
+    pub fn cancel(&self, request_id: &str, owner: Option<&str>) -> bool {
+        match self.tokens.lock().unwrap().get(request_id) {
+            Some(registered) if registered.owner.as_deref() == owner => {
+                registered.token.cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+// A registered generation, unregistered once dropped
+pub struct Generation {
+    request_id: String,
+    token: CancelToken,
+    tokens: Arc<Mutex<HashMap<String, Registered>>>,
+}
+
+impl Generation {
+    pub fn token(&self) -> &CancelToken {
+        &self.token
+    }
+}
+
+impl Drop for Generation {
+    fn drop(&mut self) {
+        self.tokens.lock().unwrap().remove(&self.request_id);
+    }
+}
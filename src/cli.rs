@@ -1,18 +1,45 @@
+use crate::logging::check_log_level;
 use crate::APP_VERSION;
 use clap::{App, Arg};
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::str::FromStr;
 
 pub fn cli_interface() -> clap::ArgMatches {
-    let matches = App::new("Open LLM Server")
+    cli_app().get_matches()
+}
+
+// The command line interface, separate from parsing so it can be tested
+pub fn cli_app() -> App<'static> {
+    App::new("Open LLM Server")
         .version(APP_VERSION)
         .about("Expose and run local LLMs via HTTP API using a single command.")
         .subcommand(
             App::new("run")
                 .about("Load the LLM and start the webserver")
+                .arg(
+                    Arg::new("config")
+                        .short('c')
+                        .long("config")
+                        .takes_value(true)
+                        .env("OPEN_LLM_CONFIG")
+                        .help("The path to a TOML (or YAML) config file, whose settings are overridden by any flags given"),
+                )
+                .arg(
+                    Arg::new("host")
+                        .long("host")
+                        .takes_value(true)
+                        .env("OPEN_LLM_HOST")
+                        .value_parser(clap::value_parser!(IpAddr))
+                        .help("The address to bind to, ie. 0.0.0.0 or :: to accept connections from other machines (Default: 127.0.0.1)"),
+                )
                 .arg(
                     Arg::new("port")
                         .short('p')
                         .long("port")
                         .takes_value(true)
+                        .value_parser(at_least(1u16))
+                        .env("OPEN_LLM_PORT")
                         .help("The port on which to run the server"),
                 )
                 .arg(
@@ -20,13 +47,71 @@ pub fn cli_interface() -> clap::ArgMatches {
                         .short('m')
                         .long("model")
                         .takes_value(true)
-                        .help("The path to the local LLM model file"),
+                        .env("OPEN_LLM_MODEL")
+                        .multiple_occurrences(true)
+                        .help("The path to the local LLM model file, optionally named as `name=path`. Repeat to serve several models, the first is the default"),
+                )
+                .arg(
+                    Arg::new("chat_template")
+                        .long("chat_template")
+                        .takes_value(true)
+                        .env("OPEN_LLM_CHAT_TEMPLATE")
+                        .multiple_occurrences(true)
+                        .help("The chat template models render messages with (`plain`, `vicuna`, `alpaca`, `chatml` or one from the templates file), or `name=template` for a single model. Repeat for several models (Default: plain)"),
+                )
+                .arg(
+                    Arg::new("templates_file")
+                        .long("templates_file")
+                        .takes_value(true)
+                        .env("OPEN_LLM_TEMPLATES_FILE")
+                        .help("The path to a TOML (or YAML) file of chat templates, in addition to the built-in ones"),
+                )
+                .arg(
+                    Arg::new("backend")
+                        .long("backend")
+                        .takes_value(true)
+                        .value_parser(check_backend)
+                        .env("OPEN_LLM_BACKEND")
+                        .help("What runs the models, `llama` or `mock` (a deterministic stand-in for testing, which needs no model files) (Default: llama)"),
+                )
+                .arg(
+                    Arg::new("mock_latency_ms")
+                        .long("mock_latency_ms")
+                        .takes_value(true)
+                        .value_parser(at_least(0u64))
+                        .env("OPEN_LLM_MOCK_LATENCY_MS")
+                        .help("The number of milliseconds the mock backend takes before generating the first token (Default: 0)"),
+                )
+                .arg(
+                    Arg::new("mock_tokens_per_second")
+                        .long("mock_tokens_per_second")
+                        .takes_value(true)
+                        .value_parser(token_rate)
+                        .env("OPEN_LLM_MOCK_TOKENS_PER_SECOND")
+                        .help("The number of tokens the mock backend generates per second, 0 for as fast as possible (Default: 0)"),
+                )
+                .arg(
+                    Arg::new("mock_script")
+                        .long("mock_script")
+                        .takes_value(true)
+                        .env("OPEN_LLM_MOCK_SCRIPT")
+                        .help("The path to a TOML (or YAML) file of responses the mock backend gives to matching prompts, instead of echoing them"),
+                )
+                .arg(
+                    Arg::new("mock_context_size")
+                        .long("mock_context_size")
+                        .takes_value(true)
+                        .value_parser(at_least(1usize))
+                        .env("OPEN_LLM_MOCK_CONTEXT_SIZE")
+                        .help("The number of tokens the context window of mock models holds (Default: 2048)"),
                 )
                 .arg(
                     Arg::new("temp")
                         .short('t')
                         .long("temp")
                         .takes_value(true)
+                        .value_parser(non_negative)
+                        .env("OPEN_LLM_TEMP")
                         .help("The sampling temperature the LLM should use (Default: 0.7)"),
                 )
                 .arg(
@@ -34,6 +119,8 @@ pub fn cli_interface() -> clap::ArgMatches {
                         .short('f')
                         .long("freq_penalty")
                         .takes_value(true)
+                        .value_parser(positive)
+                        .env("OPEN_LLM_FREQ_PENALTY")
                         .help("The frequency(repeat) penalty the LLM should use (Default: 1.2)"),
                 )
                 .arg(
@@ -41,6 +128,8 @@ pub fn cli_interface() -> clap::ArgMatches {
                         .short('o')
                         .long("output_tokens")
                         .takes_value(true)
+                        .value_parser(at_least(1usize))
+                        .env("OPEN_LLM_OUTPUT_TOKENS")
                         .help("The max number of output tokens you want the model to return (Default: 2048)"),
                 )
                 .arg(
@@ -48,17 +137,296 @@ pub fn cli_interface() -> clap::ArgMatches {
                         .short('n')
                         .long("num_threads")
                         .takes_value(true)
+                        .value_parser(at_least(1u16))
+                        .env("OPEN_LLM_NUM_THREADS")
                         .help("Number of threads the LLM should use (Default: 8)"),
                 )
+                .arg(
+                    Arg::new("max_temp")
+                        .long("max_temp")
+                        .takes_value(true)
+                        .value_parser(non_negative)
+                        .env("OPEN_LLM_MAX_TEMP")
+                        .help("The highest sampling temperature a request may ask for (Default: 2.0)"),
+                )
+                .arg(
+                    Arg::new("max_top_k")
+                        .long("max_top_k")
+                        .takes_value(true)
+                        .value_parser(at_least(1i32))
+                        .env("OPEN_LLM_MAX_TOP_K")
+                        .help("The highest top_k a request may ask for (Default: 100)"),
+                )
+                .arg(
+                    Arg::new("max_repeat_penalty")
+                        .long("max_repeat_penalty")
+                        .takes_value(true)
+                        .value_parser(positive)
+                        .env("OPEN_LLM_MAX_REPEAT_PENALTY")
+                        .help("The highest repeat penalty a request may ask for (Default: 2.0)"),
+                )
+                .arg(
+                    Arg::new("max_output_tokens")
+                        .long("max_output_tokens")
+                        .takes_value(true)
+                        .value_parser(at_least(1usize))
+                        .env("OPEN_LLM_MAX_OUTPUT_TOKENS")
+                        .help("The most output tokens a request may ask for (Default: 4096)"),
+                )
+                .arg(
+                    Arg::new("max_stop_sequences")
+                        .long("max_stop_sequences")
+                        .takes_value(true)
+                        .value_parser(at_least(0usize))
+                        .env("OPEN_LLM_MAX_STOP_SEQUENCES")
+                        .help("The most stop sequences a request may provide (Default: 4)"),
+                )
+                .arg(
+                    Arg::new("queue_depth")
+                        .long("queue_depth")
+                        .takes_value(true)
+                        .value_parser(at_least(0usize))
+                        .env("OPEN_LLM_QUEUE_DEPTH")
+                        .help("The max number of requests waiting for the LLM before new ones are rejected (Default: 16)"),
+                )
+                .arg(
+                    Arg::new("queue_timeout")
+                        .long("queue_timeout")
+                        .takes_value(true)
+                        .value_parser(at_least(1u64))
+                        .env("OPEN_LLM_QUEUE_TIMEOUT")
+                        .help("The max number of seconds a request waits for the LLM before timing out (Default: 300)"),
+                )
+                .arg(
+                    Arg::new("job_ttl")
+                        .long("job_ttl")
+                        .takes_value(true)
+                        .value_parser(at_least(0u64))
+                        .env("OPEN_LLM_JOB_TTL")
+                        .help("The number of seconds finished jobs are kept around for (Default: 3600)"),
+                )
+                .arg(
+                    Arg::new("session_ttl")
+                        .long("session_ttl")
+                        .takes_value(true)
+                        .value_parser(at_least(1u64))
+                        .env("OPEN_LLM_SESSION_TTL")
+                        .help("The number of seconds a conversation session is kept around for after its last message (Default: 3600)"),
+                )
+                .arg(
+                    Arg::new("memory_budget")
+                        .long("memory_budget")
+                        .takes_value(true)
+                        .value_parser(memory_budget)
+                        .env("OPEN_LLM_MEMORY_BUDGET")
+                        .help("The max number of megabytes loaded models may use together, 0 for no limit (Default: 0)"),
+                )
+                .arg(
+                    Arg::new("model_idle_timeout")
+                        .long("model_idle_timeout")
+                        .takes_value(true)
+                        .value_parser(at_least(0u64))
+                        .env("OPEN_LLM_MODEL_IDLE_TIMEOUT")
+                        .help("The number of seconds after which an unused model is unloaded, 0 to keep models loaded (Default: 0)"),
+                )
+                .arg(
+                    Arg::new("requests_per_minute")
+                        .long("requests_per_minute")
+                        .takes_value(true)
+                        .value_parser(at_least(0u32))
+                        .env("OPEN_LLM_REQUESTS_PER_MINUTE")
+                        .help("The max number of requests each client may make per minute, 0 for no limit (Default: 0)"),
+                )
+                .arg(
+                    Arg::new("tokens_per_day")
+                        .long("tokens_per_day")
+                        .takes_value(true)
+                        .value_parser(at_least(0u64))
+                        .env("OPEN_LLM_TOKENS_PER_DAY")
+                        .help("The max number of tokens generated for each client per day, 0 for no limit (Default: 0)"),
+                )
                 .arg(
                     Arg::new("api_key")
                         .short('a')
                         .long("api-key")
                         .takes_value(true)
+                        .env("OPEN_LLM_API_KEY")
+                        .hide_env_values(true)
                         .help("The API key to protect the server"),
+                )
+                .arg(
+                    Arg::new("api_key_file")
+                        .long("api-key-file")
+                        .takes_value(true)
+                        .env("OPEN_LLM_API_KEY_FILE")
+                        .help("The path to a file holding the API key, so it doesn't show up in the process list"),
+                )
+                .arg(
+                    Arg::new("keys_file")
+                        .long("keys_file")
+                        .takes_value(true)
+                        .env("OPEN_LLM_KEYS_FILE")
+                        .help("The path to a TOML (or YAML) file of named API keys, with the endpoints, models and usage each may have"),
+                )
+                .arg(
+                    Arg::new("tls_cert")
+                        .long("tls_cert")
+                        .takes_value(true)
+                        .env("OPEN_LLM_TLS_CERT")
+                        .help("The path to a PEM certificate chain, to serve over HTTPS"),
+                )
+                .arg(
+                    Arg::new("tls_key")
+                        .long("tls_key")
+                        .takes_value(true)
+                        .env("OPEN_LLM_TLS_KEY")
+                        .help("The path to the PEM private key of the TLS certificate"),
+                )
+                .arg(
+                    Arg::new("log_level")
+                        .long("log_level")
+                        .takes_value(true)
+                        .value_parser(check_log_level)
+                        .env("OPEN_LLM_LOG_LEVEL")
+                        .help("The level to log at (error, warn, info, debug or trace), or filter directives such as `open_llm_server=debug` (Default: info)"),
+                )
+                .arg(
+                    Arg::new("log_format")
+                        .long("log_format")
+                        .takes_value(true)
+                        .value_parser(check_log_format)
+                        .env("OPEN_LLM_LOG_FORMAT")
+                        .help("The format to log in, `text` or `json` (Default: text)"),
+                )
+                .arg(
+                    Arg::new("log_prompts")
+                        .long("log_prompts")
+                        .takes_value(true)
+                        .value_parser(clap::value_parser!(bool))
+                        .env("OPEN_LLM_LOG_PROMPTS")
+                        .help("Whether prompt text is logged (at the debug level), `true` or `false` (Default: false)"),
+                )
+                .arg(
+                    Arg::new("dry_run")
+                        .long("dry-run")
+                        .help("Print the resolved configuration and exit, without loading any models"),
+                ),
+        )
+        .subcommand(
+            App::new("hash-key")
+                .about("Prints the hash of an API key, to put in the keys file")
+                .arg(
+                    Arg::new("key")
+                        .takes_value(true)
+                        .help("The key to hash, read from stdin if not given"),
                 ),
         )
         .subcommand(App::new("help").about("Prints help information"))
-        .get_matches();
-    return matches;
+}
+
+// The slowest the mock backend may generate tokens, per second
+const MIN_TOKEN_RATE: f32 = 0.001;
+
+// Value parser for whole numbers which must be at least `min`
+fn at_least<T>(min: T) -> impl Fn(&str) -> Result<T, String> + Clone + Send + Sync + 'static
+where
+    T: FromStr + PartialOrd + Display + Copy + Send + Sync + 'static,
+{
+    move |value| {
+        let number = value
+            .parse::<T>()
+            .map_err(|_| format!("`{}` is not a whole number in the supported range", value))?;
+        check_at_least(number, min)
+    }
+}
+
+// Value parser for the memory budget, in megabytes
+fn memory_budget(value: &str) -> Result<u64, String> {
+    check_memory_budget(at_least(0u64)(value)?)
+}
+
+// Value parser for the mock backend's tokens per second
+fn token_rate(value: &str) -> Result<f32, String> {
+    check_token_rate(parse_decimal(value)?)
+}
+
+// Value parser for decimal numbers which must be greater than zero
+fn positive(value: &str) -> Result<f32, String> {
+    check_positive(parse_decimal(value)?)
+}
+
+// Value parser for decimal numbers which must not be negative
+fn non_negative(value: &str) -> Result<f32, String> {
+    check_non_negative(parse_decimal(value)?)
+}
+
+fn parse_decimal(value: &str) -> Result<f32, String> {
+    value
+        .parse::<f32>()
+        .map_err(|_| format!("`{}` is not a valid number", value))
+}
+
+// The range checks below are shared with the config file, so both accept the same values
+
+pub fn check_at_least<T: PartialOrd + Display>(value: T, min: T) -> Result<T, String> {
+    if value >= min {
+        Ok(value)
+    } else {
+        Err(format!("must be at least {}", min))
+    }
+}
+
+// The memory budget is given in megabytes, and must still fit in a u64 once counted in bytes
+pub fn check_memory_budget(value: u64) -> Result<u64, String> {
+    check_at_most(value, u64::MAX >> 20)
+}
+
+fn check_at_most<T: PartialOrd + Display>(value: T, max: T) -> Result<T, String> {
+    if value <= max {
+        Ok(value)
+    } else {
+        Err(format!("must be at most {}", max))
+    }
+}
+
+// The mock backend sleeps for `1 / rate` seconds per token, which must fit in a `Duration`
+pub fn check_token_rate(value: f32) -> Result<f32, String> {
+    if value == 0.0 || (value.is_finite() && value >= MIN_TOKEN_RATE) {
+        Ok(value)
+    } else {
+        Err(format!(
+            "must be 0 or a number of at least {}",
+            MIN_TOKEN_RATE
+        ))
+    }
+}
+
+pub fn check_backend(value: &str) -> Result<String, String> {
+    match value {
+        "llama" | "mock" => Ok(value.to_string()),
+        _ => Err("must be either `llama` or `mock`".to_string()),
+    }
+}
+
+pub fn check_log_format(value: &str) -> Result<String, String> {
+    match value {
+        "text" | "json" => Ok(value.to_string()),
+        _ => Err("must be either `text` or `json`".to_string()),
+    }
+}
+
+pub fn check_positive(value: f32) -> Result<f32, String> {
+    if value.is_finite() && value > 0.0 {
+        Ok(value)
+    } else {
+        Err("must be a number greater than 0".to_string())
+    }
+}
+
+pub fn check_non_negative(value: f32) -> Result<f32, String> {
+    if value.is_finite() && value >= 0.0 {
+        Ok(value)
+    } else {
+        Err("must be a number of at least 0".to_string())
+    }
 }
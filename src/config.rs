@@ -0,0 +1,305 @@
+use crate::cli::{
+    check_at_least, check_backend, check_log_format, check_memory_budget, check_non_negative,
+    check_positive, check_token_rate,
+};
+use crate::fs_reading::load_toml_or_yaml;
+use crate::logging::check_log_level;
+use crate::registry::ModelSpec;
+use serde::de::{self, value::MapAccessDeserializer, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::net::IpAddr;
+
+// Struct to represent the config file given with `--config`.
+// Every setting is optional, and is overridden by the matching environment variable or flag.
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub host: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub api_key: Option<String>,
+    pub api_key_file: Option<String>,
+    pub keys_file: Option<String>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub num_threads: Option<u16>,
+    pub backend: Option<String>,
+    pub models: Option<Vec<ConfigModel>>,
+    // The chat template of models which don't name their own
+    pub chat_template: Option<String>,
+    pub templates_file: Option<String>,
+    #[serde(default)]
+    pub sampling: SamplingConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default)]
+    pub mock: MockConfig,
+}
+
+// A model in the config file, as either `"path"`, `"name=path"` or `{ name, path, template }`
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum ConfigModel {
+    Spec(String),
+    Table(ModelTable),
+}
+
+// Struct to represent a model given as a table in the config file
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ModelTable {
+    name: Option<String>,
+    path: String,
+    template: Option<String>,
+}
+
+// Deserialized by hand rather than as an untagged enum, which would replace an error in a
+// table (ie. an unknown key) with one saying the model matches neither form
+impl<'de> Deserialize<'de> for ConfigModel {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ConfigModelVisitor;
+
+        impl<'de> Visitor<'de> for ConfigModelVisitor {
+            type Value = ConfigModel;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(
+                    "a model path, `name=path` or a table with `name`, `path` and `template`",
+                )
+            }
+
+            fn visit_str<E: de::Error>(self, spec: &str) -> Result<ConfigModel, E> {
+                Ok(ConfigModel::Spec(spec.to_string()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<ConfigModel, A::Error> {
+                ModelTable::deserialize(MapAccessDeserializer::new(map)).map(ConfigModel::Table)
+            }
+        }
+
+        deserializer.deserialize_any(ConfigModelVisitor)
+    }
+}
+
+// The default sampling parameters
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SamplingConfig {
+    #[serde(serialize_with = "serialize_decimal")]
+    pub temp: Option<f32>,
+    #[serde(serialize_with = "serialize_decimal")]
+    pub freq_penalty: Option<f32>,
+    pub output_tokens: Option<usize>,
+}
+
+// Limits on per-request sampling parameters, the queue, jobs, memory and per-client usage
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+    #[serde(serialize_with = "serialize_decimal")]
+    pub max_temp: Option<f32>,
+    pub max_top_k: Option<i32>,
+    #[serde(serialize_with = "serialize_decimal")]
+    pub max_repeat_penalty: Option<f32>,
+    pub max_output_tokens: Option<usize>,
+    pub max_stop_sequences: Option<usize>,
+    pub queue_depth: Option<usize>,
+    pub queue_timeout: Option<u64>,
+    pub job_ttl: Option<u64>,
+    pub session_ttl: Option<u64>,
+    pub memory_budget: Option<u64>,
+    pub model_idle_timeout: Option<u64>,
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_day: Option<u64>,
+}
+
+// What the server logs
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub level: Option<String>,
+    pub format: Option<String>,
+    pub log_prompts: Option<bool>,
+}
+
+// How the mock backend responds
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct MockConfig {
+    pub latency_ms: Option<u64>,
+    #[serde(serialize_with = "serialize_decimal")]
+    pub tokens_per_second: Option<f32>,
+    pub script: Option<String>,
+    pub context_size: Option<usize>,
+}
+
+impl ConfigFile {
+    // Apply the same range checks as the command line flags
+    fn validate(&self) -> Result<(), String> {
+        let sampling = &self.sampling;
+        let limits = &self.limits;
+        check("port", self.port, |v| check_at_least(v, 1))?;
+        check("num_threads", self.num_threads, |v| check_at_least(v, 1))?;
+        check("backend", self.backend.as_deref(), |v| {
+            check_backend(v).map(|_| v)
+        })?;
+        check("sampling.temp", sampling.temp, check_non_negative)?;
+        check(
+            "sampling.freq_penalty",
+            sampling.freq_penalty,
+            check_positive,
+        )?;
+        check("sampling.output_tokens", sampling.output_tokens, |v| {
+            check_at_least(v, 1)
+        })?;
+        check("limits.max_temp", limits.max_temp, check_non_negative)?;
+        check("limits.max_top_k", limits.max_top_k, |v| {
+            check_at_least(v, 1)
+        })?;
+        check(
+            "limits.max_repeat_penalty",
+            limits.max_repeat_penalty,
+            check_positive,
+        )?;
+        check("limits.max_output_tokens", limits.max_output_tokens, |v| {
+            check_at_least(v, 1)
+        })?;
+        check("limits.queue_timeout", limits.queue_timeout, |v| {
+            check_at_least(v, 1)
+        })?;
+        check("limits.session_ttl", limits.session_ttl, |v| {
+            check_at_least(v, 1)
+        })?;
+        check(
+            "limits.memory_budget",
+            limits.memory_budget,
+            check_memory_budget,
+        )?;
+        check("logging.level", self.logging.level.as_deref(), |v| {
+            check_log_level(v).map(|_| v)
+        })?;
+        check("logging.format", self.logging.format.as_deref(), |v| {
+            check_log_format(v).map(|_| v)
+        })?;
+        check(
+            "mock.tokens_per_second",
+            self.mock.tokens_per_second,
+            check_token_rate,
+        )?;
+        check("mock.context_size", self.mock.context_size, |v| {
+            check_at_least(v, 1)
+        })?;
+        Ok(())
+    }
+}
+
+// Serializes an f32 as the shortest decimal which reads back as the same value,
+// rather than its exact (and noisy) value as an f64
+fn serialize_decimal<S: Serializer>(value: &Option<f32>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => {
+            serializer.serialize_some(&value.to_string().parse::<f64>().unwrap_or(*value as f64))
+        }
+        None => serializer.serialize_none(),
+    }
+}
+
+// Runs a range check on a setting, if it is set
+fn check<T>(key: &str, value: Option<T>, f: impl Fn(T) -> Result<T, String>) -> Result<(), String> {
+    match value {
+        Some(value) => f(value).map(|_| ()).map_err(|e| format!("`{}` {}", key, e)),
+        None => Ok(()),
+    }
+}
+
+impl From<&ModelSpec> for ConfigModel {
+    fn from(spec: &ModelSpec) -> Self {
+        ConfigModel::Table(ModelTable {
+            name: Some(spec.name.clone()),
+            path: spec.path.clone(),
+            template: spec.template.clone(),
+        })
+    }
+}
+
+impl ConfigModel {
+    pub fn spec(&self) -> ModelSpec {
+        match self {
+            ConfigModel::Spec(spec) => ModelSpec::parse(spec),
+            ConfigModel::Table(ModelTable {
+                name: Some(name),
+                path,
+                template,
+            }) => ModelSpec {
+                name: name.clone(),
+                path: path.clone(),
+                template: template.clone(),
+            },
+            ConfigModel::Table(ModelTable {
+                name: None,
+                path,
+                template,
+            }) => ModelSpec {
+                template: template.clone(),
+                ..ModelSpec::parse(path)
+            },
+        }
+    }
+}
+
+// Reads and validates the config file.
+// Unknown keys are an error, so typos don't silently fall back to the defaults.
+pub fn load_config(path: &str) -> Result<ConfigFile, String> {
+    let config: ConfigFile = load_toml_or_yaml(path, "config file")?;
+    config
+        .validate()
+        .map_err(|e| format!("Invalid config file {}: {}", path, e))?;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn models_are_read_in_every_form() {
+        let config: ConfigFile = toml::from_str(
+            r#"
+models = ["a.bin", "b=b.bin", { path = "c.bin", template = "chatml" }]
+"#,
+        )
+        .unwrap();
+        let specs: Vec<ModelSpec> = config.models.unwrap().iter().map(|m| m.spec()).collect();
+        assert_eq!(specs[0].name, "a");
+        assert_eq!(specs[1].name, "b");
+        assert_eq!(specs[2].name, "c");
+        assert_eq!(specs[2].template.as_deref(), Some("chatml"));
+    }
+
+    #[test]
+    fn mock_token_rates_too_slow_to_sleep_for_are_an_error() {
+        let config: ConfigFile = toml::from_str("[mock]\ntokens_per_second = 1e-30\n").unwrap();
+        assert!(config.validate().is_err());
+        let config: ConfigFile = toml::from_str("[mock]\ntokens_per_second = 0.0\n").unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn unknown_model_keys_are_an_error() {
+        let toml = r#"
+[[models]]
+path = "a.bin"
+tempalte = "chatml"
+"#;
+        let error = toml::from_str::<ConfigFile>(toml).unwrap_err().to_string();
+        assert!(error.contains("unknown field `tempalte`"), "{}", error);
+
+        let yaml = "models:\n  - path: a.bin\n    tempalte: chatml\n";
+        let error = serde_yaml::from_str::<ConfigFile>(yaml)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("unknown field `tempalte`"), "{}", error);
+    }
+}
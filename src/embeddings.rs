@@ -0,0 +1,89 @@
+use crate::error::LLMError;
+use llm_chain_llama_sys::{
+    llama_context, llama_context_default_params, llama_eval, llama_free, llama_get_embeddings,
+    llama_init_from_file, llama_n_ctx, llama_n_embd, llama_token, llama_tokenize,
+};
+use std::ffi::CString;
+
+// A llama.cpp context loaded in embedding mode, which turns text into embedding vectors
+pub struct EmbeddingContext {
+    ctx: *mut llama_context,
+    num_threads: u16,
+}
+
+impl EmbeddingContext {
+    // Load the model at the given path with embeddings enabled
+    pub fn new(model_path: &str, num_threads: u16) -> Result<Self, LLMError> {
+        let c_path = CString::new(model_path)
+            .map_err(|_| LLMError::Custom("Invalid model path".to_string()))?;
+        let mut params = unsafe { llama_context_default_params() };
+        params.embedding = true;
+        let ctx = unsafe { llama_init_from_file(c_path.as_ptr(), params) };
+        if ctx.is_null() {
+            return Err(LLMError::InitializingLLMFailed);
+        }
+        Ok(Self { ctx, num_threads })
+    }
+
+    // The number of dimensions of the produced embedding vectors
+    pub fn dimension(&self) -> usize {
+        unsafe { llama_n_embd(self.ctx) as usize }
+    }
+
+    // Evaluate the input text and return the embedding vector of the final token
+    pub fn embed(&mut self, input_text: &str) -> Result<Vec<f32>, LLMError> {
+        let c_text = CString::new(input_text).map_err(|_| {
+            LLMError::InvalidParameters("the input contains a null byte".to_string())
+        })?;
+
+        // Tokenize the input (there can never be more tokens than bytes, plus the BOS token)
+        let mut tokens: Vec<llama_token> = Vec::with_capacity(input_text.len() + 1);
+        let n_tokens = unsafe {
+            llama_tokenize(
+                self.ctx,
+                c_text.as_ptr(),
+                tokens.as_mut_ptr(),
+                tokens.capacity() as i32,
+                true,
+            )
+        };
+        if n_tokens < 0 {
+            return Err(LLMError::Custom("Failed to tokenize input".to_string()));
+        }
+        unsafe { tokens.set_len(n_tokens as usize) };
+        if n_tokens > unsafe { llama_n_ctx(self.ctx) } {
+            return Err(LLMError::InvalidParameters(
+                "the input is too long for the model's context window".to_string(),
+            ));
+        }
+
+        // Evaluate the whole input from a fresh state
+        let res = unsafe {
+            llama_eval(
+                self.ctx,
+                tokens.as_ptr(),
+                n_tokens,
+                0,
+                self.num_threads as i32,
+            )
+        };
+        if res != 0 {
+            return Err(LLMError::Custom("Failed to evaluate input".to_string()));
+        }
+
+        // Copy out the embeddings before the next evaluation overwrites them
+        let embeddings =
+            unsafe { std::slice::from_raw_parts(llama_get_embeddings(self.ctx), self.dimension()) };
+        Ok(embeddings.to_vec())
+    }
+}
+
+// Like the prompt cache, the context is only ever used by whichever request holds its model's
+// RequestQueue guard, so never from two threads at once
+unsafe impl Send for EmbeddingContext {}
+
+impl Drop for EmbeddingContext {
+    fn drop(&mut self) {
+        unsafe { llama_free(self.ctx) };
+    }
+}
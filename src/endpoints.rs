@@ -1,28 +1,122 @@
+use crate::admin;
+use crate::cancel::CancelOnDrop;
 use crate::error::LLMError;
-use crate::llm_interface::LLMInterface;
+use crate::health;
+use crate::jobs;
+use crate::keyring::ApiKey;
+use crate::llm_interface::take_generation_stats;
+use crate::logging::RequestId;
+use crate::metrics;
+use crate::openai;
+use crate::queue::{QueueError, QueueGuard};
+use crate::ratelimit::Client;
+use crate::registry::{Model, Usage};
+use crate::responses::{error_response, json_response, prompt_response, PromptResponse};
+use crate::sampling::{Sampling, SamplingParams};
+use crate::sessions;
+use crate::state::ServerState;
+use crate::templates::{self, ChatMessage};
 use crate::APP_VERSION;
-use futures::Future;
+use futures::channel::mpsc;
+use futures::{future, stream, Future, StreamExt};
 use hyper::body::to_bytes;
-use hyper::header;
+use hyper::{header, Method, StatusCode};
 use hyper::{Body, Request, Response};
-use llm_chain_llama::Executor as LlamaExecutor;
 use serde::{Deserialize, Serialize};
-use serde_json;
 use std::sync::Arc;
 use tokio::sync::oneshot;
-use tokio::sync::Mutex;
+use tracing::{warn, Instrument, Span};
+use uuid::Uuid;
 
 // Struct to represent submit prompt input
 #[derive(Serialize, Deserialize, Debug)]
-struct PromptInput {
+pub struct PromptInput {
+    pub prompt: String,
+    // The model to run the prompt on, the default model if not given
+    pub model: Option<String>,
+    // Optional per-request overrides of the sampling parameters
+    #[serde(flatten)]
+    pub sampling: SamplingParams,
+}
+
+// Struct to represent chat input
+#[derive(Serialize, Deserialize, Debug)]
+struct ChatInput {
+    // The conversation so far, which the model replies to
+    messages: Vec<ChatMessage>,
+    // The model to chat with, the default model if not given
+    model: Option<String>,
+    // Whether to stream the reply back as Server-Sent Events
+    #[serde(default)]
+    stream: bool,
+    // Optional per-request overrides of the sampling parameters
+    #[serde(flatten)]
+    sampling: SamplingParams,
+}
+
+// A prompt request, validated against the model it is for before it is queued
+struct PromptJob {
     prompt: String,
+    sampling: Sampling,
+}
+
+// A chat request, rendered with the model's template before it is queued
+struct ChatJob {
+    prompt: String,
+    sampling: Sampling,
+    stream: bool,
+}
+
+// Struct to represent a single token event of a streamed prompt response
+#[derive(Serialize)]
+struct PromptTokenEvent {
+    token: String,
+}
+
+// Struct to represent generate embeddings input
+#[derive(Serialize, Deserialize, Debug)]
+struct EmbeddingsInput {
+    input: EmbeddingsInputText,
+}
+
+// Either a single string or a batch of strings to embed
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+enum EmbeddingsInputText {
+    Single(String),
+    Batch(Vec<String>),
+}
+
+// Struct to represent a generate embeddings response
+#[derive(Serialize)]
+struct EmbeddingsResponse {
+    success: bool,
+    dimension: usize,
+    embeddings: Vec<Vec<f32>>,
 }
 
-// Struct to represent a submit prompt response
+// The model a request selects, read before the request is queued
+#[derive(Deserialize, Default)]
+struct ModelSelector {
+    model: Option<String>,
+}
+
+// Struct to represent the models endpoint response
 #[derive(Serialize)]
-struct PromptResponse {
+struct ModelsResponse {
     success: bool,
-    response: String,
+    default: Option<String>,
+    models: Vec<ModelStatus>,
+}
+
+// Struct to represent a single model of the models endpoint response
+#[derive(Serialize)]
+struct ModelStatus {
+    name: String,
+    path: String,
+    loaded: bool,
+    is_busy: bool,
+    queued: usize,
 }
 
 // Struct to represent the is_busy endpoint response
@@ -30,93 +124,116 @@ struct PromptResponse {
 struct IsBusyResponse {
     success: bool,
     is_busy: bool,
+    queued: usize,
 }
 
 impl IsBusyResponse {
-    async fn new(llm: Arc<Mutex<LLMInterface<LlamaExecutor>>>, endpoint_success: bool) -> Self {
-        // Attempt to acquire the LLM mutex lock
-        let is_available = llm.try_lock();
-        // Determine whether the lock was acquired and create the response object
-        let resp = Self {
+    async fn new(state: &ServerState, endpoint_success: bool) -> Self {
+        // Check whether a request holds the default model, and how many are waiting for it
+        let model = state.models.default_model();
+        Self {
             success: endpoint_success,
-            is_busy: is_available.is_err(),
-        };
-        drop(is_available);
-        return resp;
+            is_busy: model.map(|m| m.queue.is_busy()).unwrap_or(false),
+            queued: model.map(|m| m.queue.queued()).unwrap_or(0),
+        }
     }
 }
 
 // Routes requests based on their URI
 pub async fn route_requests(
     req: Request<Body>,
-    llm: Arc<Mutex<LLMInterface<LlamaExecutor>>>,
+    state: Arc<ServerState>,
 ) -> Result<Response<Body>, LLMError> {
-    // Pre-check if the LLM is busy before doing any other routing
-    let response = IsBusyResponse::new(Arc::clone(&llm), false).await;
-    if response.is_busy && req.uri().path() != "/is_busy" {
-        return is_busy_http_response(response).await;
-    }
-
-    // Check if there is an API key/run checks
-    let llm_clone = Arc::clone(&llm);
-    if let Err(e) = check_api_key(&req, &llm_clone).await {
-        let error_msg = format!("{}", e);
-        return Ok(Response::builder()
-            .status(hyper::StatusCode::UNAUTHORIZED)
-            .body(Body::from(error_msg))
-            .unwrap());
-    }
-
-    // If the LLM isn't busy and API checks pass,
-    // match the URI path to the appropriate endpoint function
-    // and return the result
+    // Match the URI path to the appropriate
+    // endpoint function and return the result.
+    // Requests which need the LLM wait for their turn in the queue.
     match req.uri().path() {
         // Root endpoint
         "/" => root_endpoint(req).await,
         // Spawn a new task to handle a prompt request and return the result
-        "/submit_prompt" => spawn_and_get_result(req, llm, submit_prompt_endpoint).await,
+        "/submit_prompt" => {
+            spawn_and_get_result(
+                req,
+                state,
+                Usage::Generation,
+                parse_prompt,
+                submit_prompt_endpoint,
+            )
+            .await
+        }
         // Spawn a new task to handle generating embeddings
-        // "/generate_embeddings" => {
-        //     spawn_and_get_result(req, llm, generate_embeddings_endpoint).await
-        // }
+        "/generate_embeddings" => {
+            spawn_and_get_result(
+                req,
+                state,
+                Usage::Embeddings,
+                parse_embeddings,
+                generate_embeddings_endpoint,
+            )
+            .await
+        }
         // Handle a prompt request by streaming the result back to the client
-        // "/submit_prompt_streaming" => {
-        //     spawn_and_get_result(req, llm, submit_prompt_streaming_endpoint).await
-        // }
+        "/submit_prompt_streaming" => {
+            spawn_and_get_result(
+                req,
+                state,
+                Usage::Generation,
+                parse_prompt,
+                submit_prompt_streaming_endpoint,
+            )
+            .await
+        }
+        // Chat with a model, rendering the messages with the model's template
+        "/chat" => {
+            spawn_and_get_result(req, state, Usage::Generation, parse_chat, chat_endpoint).await
+        }
+        // Conversations whose history is kept by the server
+        "/sessions" => sessions::create_session_endpoint(req, state).await,
+        path if path.starts_with("/sessions/") => sessions::session_endpoint(req, state).await,
+        // OpenAI-compatible API
+        "/v1/completions" => {
+            spawn_and_get_result(
+                req,
+                state,
+                Usage::Generation,
+                openai::parse_completions,
+                openai::completions_endpoint,
+            )
+            .await
+        }
+        "/v1/chat/completions" => {
+            spawn_and_get_result(
+                req,
+                state,
+                Usage::Generation,
+                openai::parse_chat_completions,
+                openai::completions_endpoint,
+            )
+            .await
+        }
+        "/v1/models" => openai::models_endpoint(&state).await,
+        // List the models being served
+        "/models" => models_endpoint(&state).await,
+        // Asynchronous jobs API
+        "/jobs" => jobs::submit_job_endpoint(req, state).await,
+        path if path.starts_with("/jobs/") => jobs::job_endpoint(req, &state).await,
+        // Cancel an in-flight generation by its request id
+        path if path.starts_with("/cancel/") => cancel_endpoint(req, &state).await,
+        // Reload a model without restarting the server
+        "/admin/reload" => admin::reload_endpoint(req, &state).await,
         // Return a response indicating whether the LLM is currently locked.
         // This endpoint is required for setting the success value properly.
-        "/is_busy" => is_busy_endpoint(llm).await,
+        "/is_busy" => is_busy_endpoint(&state).await,
+        // Liveness and readiness checks, ie. for Kubernetes probes
+        "/health/live" => health::live_endpoint(&state).await,
+        "/health/ready" => health::ready_endpoint(req, &state).await,
+        // Prometheus metrics
+        "/metrics" => metrics::metrics_endpoint(&state).await,
         // Return an empty response for any other path
         _ => Ok(Response::new(Body::empty())),
     }
 }
 
-// Verifies that the api key checks pass
-async fn check_api_key(
-    req: &Request<Body>,
-    llm: &Arc<Mutex<LLMInterface<LlamaExecutor>>>,
-) -> Result<(), LLMError> {
-    // Lock the LLM and check if there is an API key
-    let llm_guard = llm.lock().await;
-    if let Some(api_key) = &llm_guard.api_key {
-        // Check if the request includes an 'Authorization' header
-        if let Some(auth_header) = req.headers().get("Authorization") {
-            // If the header is not equal to the API key, return an error
-            if auth_header != api_key {
-                return Err(LLMError::Custom("Invalid API key".into()));
-            }
-        } else {
-            // If no 'Authorization' header is present, return an error
-            return Err(LLMError::Custom("No API key provided".into()));
-        }
-    }
-    // Drop the lock
-    drop(llm_guard);
-
-    // If we reached this point, the API key is valid or there was no API key to check
-    Ok(())
-}
-
 // Basic root endpoint
 async fn root_endpoint(_req: Request<Body>) -> Result<Response<Body>, LLMError> {
     let response_body = "Open LLM Server v".to_string() + APP_VERSION;
@@ -128,198 +245,448 @@ async fn root_endpoint(_req: Request<Body>) -> Result<Response<Body>, LLMError>
     Ok(response)
 }
 
+// Cancels the generation with the request id given in the path
+async fn cancel_endpoint(
+    req: Request<Body>,
+    state: &ServerState,
+) -> Result<Response<Body>, LLMError> {
+    if req.method() != Method::POST {
+        return error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+    }
+    let request_id = req.uri().path().trim_start_matches("/cancel/");
+    // Only the key which started a generation may cancel it
+    let owner = req
+        .extensions()
+        .get::<Arc<ApiKey>>()
+        .map(|key| key.name.as_str());
+    if state.generations.cancel(request_id, owner) {
+        prompt_response(StatusCode::OK, true, "Generation cancelled")
+    } else {
+        error_response(StatusCode::NOT_FOUND, "Generation not found")
+    }
+}
+
+// Lists every model being served, and whether each is loaded and busy
+async fn models_endpoint(state: &ServerState) -> Result<Response<Body>, LLMError> {
+    let response = ModelsResponse {
+        success: true,
+        default: state.models.default_model().map(|m| m.name.clone()),
+        models: state
+            .models
+            .iter()
+            .map(|m| ModelStatus {
+                name: m.name.clone(),
+                path: m.path(),
+                loaded: m.queue.is_loaded(),
+                is_busy: m.queue.is_busy(),
+                queued: m.queue.queued(),
+            })
+            .collect(),
+    };
+    json_response(StatusCode::OK, &response)
+}
+
+// Builds the response for a failed request, in OpenAI's error format for the `/v1/` routes
+// as OpenAI clients expect, or in the shape of a prompt response for the rest of the API
+fn api_error_response(
+    path: &str,
+    status: StatusCode,
+    message: &str,
+) -> Result<Response<Body>, LLMError> {
+    if path.starts_with("/v1/") {
+        openai::error_response(status, message)
+    } else {
+        error_response(status, message)
+    }
+}
+
+// Builds the response for a request naming a model which is not being served
+pub fn model_not_found_response(path: &str, name: &str) -> Result<Response<Body>, LLMError> {
+    let message = format!("The model `{}` does not exist", name);
+    api_error_response(path, StatusCode::NOT_FOUND, &message)
+}
+
+// Builds the response rejecting a malformed or invalid request
+pub fn bad_request_response(path: &str, message: &str) -> Result<Response<Body>, LLMError> {
+    api_error_response(path, StatusCode::BAD_REQUEST, message)
+}
+
+// Builds the response for a request naming a model its API key may not use
+pub fn model_forbidden_response(path: &str, name: &str) -> Result<Response<Body>, LLMError> {
+    let message = format!("This API key may not use the model `{}`", name);
+    api_error_response(path, StatusCode::FORBIDDEN, &message)
+}
+
 // Returns a response indicating whether the LLM is currently locked
 // This returns success == true;
-async fn is_busy_endpoint(
-    llm: Arc<Mutex<LLMInterface<LlamaExecutor>>>,
-) -> Result<Response<Body>, LLMError> {
-    let response = IsBusyResponse::new(llm, true).await;
+async fn is_busy_endpoint(state: &ServerState) -> Result<Response<Body>, LLMError> {
+    let response = IsBusyResponse::new(state, true).await;
     is_busy_http_response(response).await
 }
 
 // Takes an IsBusyResponse and builds it into a proper http response
 async fn is_busy_http_response(response: IsBusyResponse) -> Result<Response<Body>, LLMError> {
-    // Serialize the response object to JSON
-    let body = serde_json::to_string(&response)?;
-    // Return a new response with the JSON content
-    Ok(Response::builder()
-        .header(header::CONTENT_TYPE, "application/json")
-        .body(Body::from(body))?)
+    json_response(StatusCode::OK, &response)
+}
+
+// Parse and validate a prompt request.
+// A body which isn't valid UTF-8 or JSON is rejected like any other malformed one.
+fn parse_prompt(body: &[u8], model: &Model) -> Result<PromptJob, String> {
+    let input: PromptInput =
+        serde_json::from_slice(body).map_err(|_| "Failed to parse request body".to_string())?;
+    let sampling = model
+        .sampling(&input.sampling)
+        .map_err(|error| error.to_string())?;
+    Ok(PromptJob {
+        prompt: input.prompt,
+        sampling,
+    })
 }
 
 // Handle a prompt request and send the response through a channel
 async fn submit_prompt_endpoint(
-    mut req: Request<Body>,
-    llm: Arc<Mutex<LLMInterface<LlamaExecutor>>>,
+    job: PromptJob,
+    llm_guard: QueueGuard,
     tx: oneshot::Sender<Result<Response<Body>, LLMError>>,
 ) {
-    // Extract the body from the request and convert it to a string
-    let body_bytes = to_bytes(req.body_mut()).await.unwrap();
-    let body_str = String::from_utf8(body_bytes.to_vec()).unwrap();
+    run_prompt(&job.prompt, &job.sampling, llm_guard, tx).await
+}
 
-    // Deserialize the body string into a PromptInput struct
-    let input: PromptInput = match serde_json::from_str(&body_str) {
-        Ok(input) => input,
-        Err(_) => {
-            if tx
-                .send(Err(LLMError::Custom(
-                    "Failed to parse request body".to_string(),
-                )))
-                .is_err()
-            {
-                eprintln!("Failed to send prompt response.");
-            }
-            return;
-        }
-    };
+// Parse a chat request, and render its messages with the model's template
+fn parse_chat(body: &[u8], model: &Model) -> Result<ChatJob, String> {
+    let input: ChatInput =
+        serde_json::from_slice(body).map_err(|_| "Failed to parse request body".to_string())?;
+    templates::check_messages(&input.messages)?;
+    let mut sampling = model
+        .sampling(&input.sampling)
+        .map_err(|error| error.to_string())?;
+    sampling.add_stop_sequences(&model.template.stop);
+    Ok(ChatJob {
+        prompt: model.template.render(&input.messages),
+        sampling,
+        stream: input.stream,
+    })
+}
 
-    // Attempt to acquire the LLM mutex lock and submit the prompt
-    let content = match llm.try_lock() {
-        Ok(mut llm_guard) => llm_guard.submit_prompt(&input.prompt).await,
-        // If the LLM is locked, return an error
-        Err(_) => Err(LLMError::Custom("LLM Is Busy".to_string())),
-    };
+// Handle a chat request and send the model's reply through a channel
+async fn chat_endpoint(
+    job: ChatJob,
+    llm_guard: QueueGuard,
+    tx: oneshot::Sender<Result<Response<Body>, LLMError>>,
+) {
+    if job.stream {
+        stream_prompt(&job.prompt, &job.sampling, llm_guard, tx).await
+    } else {
+        run_prompt(&job.prompt, &job.sampling, llm_guard, tx).await
+    }
+}
 
-    // Create a response based on the result of the prompt request
-    let response = match content {
-        Ok(content) => PromptResponse {
-            success: true,
-            response: content,
-        },
-        Err(error) => PromptResponse {
-            success: false,
-            response: error.to_string(),
-        },
+// Run a prompt on the LLM and send the response through a channel
+async fn run_prompt(
+    prompt: &str,
+    sampling: &Sampling,
+    mut llm_guard: QueueGuard,
+    tx: oneshot::Sender<Result<Response<Body>, LLMError>>,
+) {
+    // Submit the prompt to the LLM
+    let content = llm_guard.submit_prompt(prompt, sampling).await;
+
+    // Create a response based on the result of the prompt request.
+    // Invalid parameters (ie. a prompt too long for the context window) are the client's
+    // mistake, like a malformed body, so they get a 400.
+    let res = match content {
+        Ok(content) => prompt_response(StatusCode::OK, true, &content),
+        Err(error @ LLMError::InvalidParameters(_)) => {
+            error_response(StatusCode::BAD_REQUEST, &error.to_string())
+        }
+        Err(error) => error_response(StatusCode::OK, &error.to_string()),
     };
 
-    // Convert the response to JSON
-    let body = match serde_json::to_string(&response) {
-        Ok(body) => body,
-        Err(_) => {
-            if tx
-                .send(Err(LLMError::Custom(
-                    "Failed to convert response to JSON".to_string(),
-                )))
-                .is_err()
-            {
-                eprintln!("Failed to send prompt response.");
-            }
-            return;
+    // Send the response through the channel
+    if tx.send(res).is_err() {
+        warn!("Failed to send prompt response");
+    }
+}
+
+// Builds the response for a request which could not get its turn on the LLM:
+// 429 if the queue is full, 503 if it timed out waiting or the model doesn't fit in memory
+// (all with a `Retry-After` header), or 500 if the model failed to load
+pub fn queue_error_response(path: &str, error: QueueError) -> Result<Response<Body>, LLMError> {
+    let status = match error {
+        QueueError::Full { .. } => StatusCode::TOO_MANY_REQUESTS,
+        QueueError::TimedOut { .. } | QueueError::OutOfMemory { .. } => {
+            StatusCode::SERVICE_UNAVAILABLE
         }
+        QueueError::LoadFailed => StatusCode::INTERNAL_SERVER_ERROR,
+        // Retrying won't help, but the model can still serve other requests
+        QueueError::TooLarge => StatusCode::SERVICE_UNAVAILABLE,
     };
+    let mut res = api_error_response(path, status, &error.to_string())?;
+    if let Some(retry_after) = error.retry_after() {
+        res.headers_mut()
+            .insert(header::RETRY_AFTER, retry_after.into());
+    }
+    Ok(res)
+}
+
+// Handle a prompt request and stream the generated tokens back as Server-Sent Events.
+// The response is sent through the channel immediately, while the body is fed as tokens arrive.
+async fn submit_prompt_streaming_endpoint(
+    job: PromptJob,
+    llm_guard: QueueGuard,
+    tx: oneshot::Sender<Result<Response<Body>, LLMError>>,
+) {
+    stream_prompt(&job.prompt, &job.sampling, llm_guard, tx).await
+}
 
-    // Create a JSON response
+// Run a prompt on the LLM, streaming the generated tokens back as Server-Sent Events
+async fn stream_prompt(
+    prompt: &str,
+    sampling: &Sampling,
+    mut llm_guard: QueueGuard,
+    tx: oneshot::Sender<Result<Response<Body>, LLMError>>,
+) {
+    // Tokens are sent as `data:` events, followed by a final `done` (or `error`) event
+    // holding the same object `/submit_prompt` would have returned
+    let (token_tx, token_rx) = mpsc::unbounded::<String>();
+    let (done_tx, done_rx) = futures::channel::oneshot::channel::<String>();
+    let events = token_rx
+        .map(|token| sse_event(None, &PromptTokenEvent { token }))
+        .chain(stream::once(done_rx).filter_map(|done| future::ready(done.ok())))
+        .map(Ok::<_, LLMError>);
+
+    // Create the event stream response and send it through the channel
     let res = Response::builder()
-        .header(header::CONTENT_TYPE, "application/json")
-        .body(Body::from(body))
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::wrap_stream(events))
         .map_err(LLMError::from);
-
-    // Send the response through the channel
     if tx.send(res).is_err() {
-        eprintln!("Failed to send prompt response.");
+        warn!("Failed to send prompt response");
+        return;
+    }
+
+    // Submit the prompt to the LLM
+    let content = llm_guard
+        .submit_prompt_streaming(prompt, sampling, token_tx)
+        .await;
+
+    // Close the stream with the final result
+    let done = match content {
+        Ok(content) => sse_event(
+            Some("done"),
+            &PromptResponse {
+                success: true,
+                response: content,
+            },
+        ),
+        Err(error) => sse_event(
+            Some("error"),
+            &PromptResponse {
+                success: false,
+                response: error.to_string(),
+            },
+        ),
+    };
+    if done_tx.send(done).is_err() {
+        warn!("Failed to send prompt response");
     }
 }
 
-// Spawns a new task to handle a request and returns the result
-async fn spawn_and_get_result<F, Fut>(
+// Formats a serializable value as a single Server-Sent Event
+pub fn sse_event<T: Serialize>(event: Option<&str>, data: &T) -> String {
+    let data = serde_json::to_string(data).unwrap_or_default();
+    match event {
+        Some(event) => format!("event: {}\ndata: {}\n\n", event, data),
+        None => format!("data: {}\n\n", data),
+    }
+}
+
+// Parses and validates the request for the model its body selects, then waits for its turn
+// on that model and spawns a new task to run it, returning the result.
+// Malformed and invalid requests are turned away before they take a place in the queue.
+async fn spawn_and_get_result<T, P, F, Fut>(
     req: Request<Body>,
-    llm: Arc<Mutex<LLMInterface<LlamaExecutor>>>,
+    state: Arc<ServerState>,
+    usage: Usage,
+    parse: P,
     func: F,
 ) -> Result<Response<Body>, LLMError>
 where
     // Define the function and future types
-    F: Fn(
-            Request<Body>,
-            Arc<Mutex<LLMInterface<LlamaExecutor>>>,
-            oneshot::Sender<Result<Response<Body>, LLMError>>,
-        ) -> Fut
+    P: FnOnce(&[u8], &Model) -> Result<T, String>,
+    T: Send + 'static,
+    F: FnOnce(T, QueueGuard, oneshot::Sender<Result<Response<Body>, LLMError>>) -> Fut
         + Send
         + 'static,
     Fut: Future<Output = ()> + 'static,
 {
+    // Read the body up front to find out which model the request is for
+    let (parts, body) = req.into_parts();
+    let body_bytes = to_bytes(body).await.unwrap_or_default();
+    let selector: ModelSelector = serde_json::from_slice(&body_bytes).unwrap_or_default();
+    let req = Request::from_parts(parts, Body::empty());
+    let model = match state.models.get(selector.model.as_deref()) {
+        Some(model) => model,
+        None => {
+            let name = selector.model.unwrap_or_default();
+            return model_not_found_response(req.uri().path(), &name);
+        }
+    };
+    let input = match parse(&body_bytes, model) {
+        Ok(input) => input,
+        Err(message) => return bad_request_response(req.uri().path(), &message),
+    };
+    let model = model.name.clone();
+    spawn_on_model(req, state, &model, usage, move |llm, tx| {
+        func(input, llm, tx)
+    })
+    .await
+}
+
+// Waits for the request's turn on the named model, loaded for the given usage,
+// then runs `func` on a new task and returns the response it sends through the channel
+pub async fn spawn_on_model<F, Fut>(
+    req: Request<Body>,
+    state: Arc<ServerState>,
+    model: &str,
+    usage: Usage,
+    func: F,
+) -> Result<Response<Body>, LLMError>
+where
+    F: FnOnce(QueueGuard, oneshot::Sender<Result<Response<Body>, LLMError>>) -> Fut
+        + Send
+        + 'static,
+    Fut: Future<Output = ()> + 'static,
+{
+    // Register the generation so it can be cancelled by the request's id, and by its key only.
+    // Clients may choose their ids, so one which is already in flight is turned away
+    // rather than leaving the first generation impossible to cancel.
+    let request_id = req
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.0.clone())
+        .unwrap_or_else(|| Uuid::new_v4().simple().to_string());
+    let owner = req
+        .extensions()
+        .get::<Arc<ApiKey>>()
+        .map(|key| key.name.as_str());
+    let generation = match state.generations.register(&request_id, owner) {
+        Some(generation) => generation,
+        None => {
+            return api_error_response(
+                req.uri().path(),
+                StatusCode::CONFLICT,
+                "A generation with this request id is already in flight",
+            )
+        }
+    };
+    // hyper drops this future if the client disconnects before the response is ready,
+    // which cancels the generation (streamed responses notice the disconnect themselves)
+    let disconnect = CancelOnDrop::new(generation.token().clone());
+
+    let model = match state.models.get(Some(model)) {
+        Some(model) => model,
+        None => return model_not_found_response(req.uri().path(), model),
+    };
+    if let Some(key) = req.extensions().get::<Arc<ApiKey>>() {
+        if !key.allows_model(&model.name) {
+            return model_forbidden_response(req.uri().path(), &model.name);
+        }
+    }
+    let client = req.extensions().get::<Arc<Client>>().cloned();
+    let metrics = Arc::clone(&state.metrics);
+
+    // Wait for exclusive access to the model
+    let llm = match state.models.acquire(model, usage).await {
+        Ok(llm) => llm,
+        Err(error) => return queue_error_response(req.uri().path(), error),
+    };
+
     // Create a new channel to receive the response
     let (tx, rx) = oneshot::channel();
-    // Spawn a new task to handle the request
-    tokio::task::spawn(async move {
-        // Use `block_in_place` to run the blocking operation on the current thread
-        // and `block_on` to wait for the future to complete.
-        // (In practice the LLM will spawn new threads anyways).
-        tokio::task::block_in_place(|| futures::executor::block_on(func(req, llm, tx)));
-    });
+    // Spawn a new task to handle the request, logging under the request's id
+    let span = Span::current();
+    tokio::task::spawn(
+        async move {
+            // Use `block_in_place` to run the blocking operation on the current thread
+            // and `block_on` to wait for the future to complete.
+            // (In practice the LLM will spawn new threads anyways).
+            tokio::task::block_in_place(|| {
+                let _cancel = generation.token().install();
+                take_generation_stats();
+                futures::executor::block_on(func(llm, tx));
+                // Count what was generated against the client's daily quota
+                let stats = take_generation_stats();
+                metrics.record_generation(&stats);
+                if let Some(client) = client {
+                    client.record_tokens(stats.completion_tokens);
+                }
+            });
+        }
+        .instrument(span),
+    );
     // Await the response from the channel or return an error if it fails
-    rx.await
-        .unwrap_or_else(|_| Err(LLMError::Custom("Failed to get response.".to_string())))
+    let res = rx
+        .await
+        .unwrap_or_else(|_| Err(LLMError::Custom("Failed to get response.".to_string())));
+    disconnect.disarm();
+    res
 }
 
-// Handle a prompt request and send the response through a channel
-// async fn generate_embeddings_endpoint(
-//     mut req: Request<Body>,
-//     llm: Arc<Mutex<LLMInterface<LlamaExecutor>>>,
-//     tx: oneshot::Sender<Result<Response<Body>, LLMError>>,
-// ) {
-//     // Extract the body from the request and convert it to a string
-//     let body_bytes = to_bytes(req.body_mut()).await.unwrap();
-//     let body_str = String::from_utf8(body_bytes.to_vec()).unwrap();
-
-//     // Deserialize the body string into a PromptInput struct
-//     let input: PromptInput = match serde_json::from_str(&body_str) {
-//         Ok(input) => input,
-//         Err(_) => {
-//             if tx
-//                 .send(Err(LLMError::Custom(
-//                     "Failed to parse request body".to_string(),
-//                 )))
-//                 .is_err()
-//             {
-//                 eprintln!("Failed to send prompt response.");
-//             }
-//             return;
-//         }
-//     };
-
-//     // Attempt to acquire the LLM mutex lock and submit the prompt
-//     let res = match llm.try_lock() {
-//         Ok(mut llm_guard) => llm_guard.generate_embeddings(&input.prompt).await,
-//         // If the LLM is locked, return an error
-//         Err(_) => Err(LLMError::Custom("LLM Is Busy".to_string())),
-//     };
-
-//     // Create a response based on the result of the prompt request
-//     let response = match res {
-//         Ok(embeddings) => PromptResponse {
-//             success: true,
-//             response: embeddings[0].to_string(),
-//         },
-//         Err(error) => PromptResponse {
-//             success: false,
-//             response: error.to_string(),
-//         },
-//     };
-
-//     // Convert the response to JSON
-//     let body = match serde_json::to_string(&response) {
-//         Ok(body) => body,
-//         Err(_) => {
-//             if tx
-//                 .send(Err(LLMError::Custom(
-//                     "Failed to convert response to JSON".to_string(),
-//                 )))
-//                 .is_err()
-//             {
-//                 eprintln!("Failed to send prompt response.");
-//             }
-//             return;
-//         }
-//     };
-
-//     // Create a JSON response
-//     let res = Response::builder()
-//         .header(header::CONTENT_TYPE, "application/json")
-//         .body(Body::from(body))
-//         .map_err(LLMError::from);
-
-//     // Send the response through the channel
-//     if tx.send(res).is_err() {
-//         eprintln!("Failed to send prompt response.");
-//     }
-// }
+// Parse an embeddings request into the inputs to embed
+fn parse_embeddings(body: &[u8], _model: &Model) -> Result<Vec<String>, String> {
+    let input: EmbeddingsInput =
+        serde_json::from_slice(body).map_err(|_| "Failed to parse request body".to_string())?;
+    let inputs = match input.input {
+        EmbeddingsInputText::Single(text) => vec![text],
+        EmbeddingsInputText::Batch(texts) => texts,
+    };
+    if inputs.is_empty() {
+        return Err("No input provided".to_string());
+    }
+    Ok(inputs)
+}
+
+// Handle an embeddings request and send the response through a channel
+async fn generate_embeddings_endpoint(
+    inputs: Vec<String>,
+    mut llm_guard: QueueGuard,
+    tx: oneshot::Sender<Result<Response<Body>, LLMError>>,
+) {
+    // Embed every input
+    let mut embeddings = Vec::with_capacity(inputs.len());
+    for text in &inputs {
+        match llm_guard.generate_embeddings(text).await {
+            Ok(embedding) => embeddings.push(embedding),
+            Err(error) => return send_embeddings_error(tx, error),
+        }
+    }
+    // Release the LLM for the next request before building the response
+    drop(llm_guard);
+
+    // Create the response and send it through the channel
+    let res = json_response(
+        StatusCode::OK,
+        &EmbeddingsResponse {
+            success: true,
+            dimension: embeddings.first().map(|e| e.len()).unwrap_or(0),
+            embeddings,
+        },
+    );
+    if tx.send(res).is_err() {
+        warn!("Failed to send embeddings response");
+    }
+}
+
+// Sends a failed embeddings response, in the same shape as a failed prompt response:
+// 400 if the input was at fault, 500 if the model failed to embed it
+fn send_embeddings_error(tx: oneshot::Sender<Result<Response<Body>, LLMError>>, error: LLMError) {
+    let status = match error {
+        LLMError::InvalidParameters(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    if tx.send(error_response(status, &error.to_string())).is_err() {
+        warn!("Failed to send embeddings response");
+    }
+}
@@ -5,6 +5,8 @@ use std::fmt;
 pub enum LLMError {
     InitializingLLMFailed,
     SubmittingPromptFailed,
+    InvalidParameters(String),
+    Cancelled,
     Custom(String),
 }
 
@@ -15,6 +17,10 @@ impl fmt::Display for LLMError {
             LLMError::SubmittingPromptFailed => {
                 write!(f, "Submitting prompt to the LLM has failed.")
             }
+            LLMError::InvalidParameters(s) => {
+                write!(f, "Invalid parameters: {s}")
+            }
+            LLMError::Cancelled => write!(f, "The generation was cancelled."),
             LLMError::Custom(s) => {
                 write!(f, "{s}")
             }
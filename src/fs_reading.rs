@@ -1,5 +1,7 @@
+use serde::de::DeserializeOwned;
 use std::fs;
 use std::path::Path;
+use tracing::error;
 
 /// Finds the first `.bin` file in the current directory and returns its path.
 /// If no `.bin` files are found, returns `None`.
@@ -27,12 +29,50 @@ pub fn find_local_model() -> Option<String> {
     None
 }
 
+// Checks whether the model file exists
+pub fn model_file_exists(model_path: &str) -> bool {
+    Path::new(model_path).exists() && Path::new(model_path).is_file()
+}
+
 // Closes the app if the model file does not exist
 pub fn model_file_close_check(model_path: &str) {
-    if !(Path::new(model_path).exists() && Path::new(model_path).is_file()) {
-        println!("Error: Model file could not be found/read, unable to start.");
-        println!("Please ensure you have a .bin in the same folder as this executable,");
-        println!("or that you specify the correct path via the '-p' option.");
+    if !model_file_exists(model_path) {
+        error!(
+            path = model_path,
+            "Model file could not be found/read, unable to start. \
+             Please ensure you have a .bin in the same folder as this executable, \
+             or that you specify the correct path via the '-m' option."
+        );
         std::process::exit(1);
     }
 }
+
+// Reads the API key from the given file, closing the app if it can't be read or is empty
+pub fn read_api_key_file(path: &str) -> String {
+    match fs::read_to_string(path) {
+        Ok(contents) if !contents.trim().is_empty() => contents.trim().to_string(),
+        Ok(_) => {
+            error!(path, "The API key file is empty, unable to start.");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            error!(path, error = %e, "The API key file could not be read, unable to start.");
+            std::process::exit(1);
+        }
+    }
+}
+
+// Reads a TOML file, or a YAML one if it has a `.yaml`/`.yml` extension.
+// `what` names the file in errors, ie. "config file".
+pub fn load_toml_or_yaml<T: DeserializeOwned>(path: &str, what: &str) -> Result<T, String> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Failed to read {} {}: {}", what, path, e))?;
+    let extension = Path::new(path)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    match extension.as_deref() {
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&contents).map_err(|e| e.to_string()),
+        _ => toml::from_str(&contents).map_err(|e| e.to_string()),
+    }
+    .map_err(|e| format!("Invalid {} {}: {}", what, path, e))
+}
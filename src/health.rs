@@ -0,0 +1,184 @@
+use crate::error::LLMError;
+use crate::fs_reading::model_file_exists;
+use crate::registry::Model;
+use crate::responses::json_response;
+use crate::state::ServerState;
+use hyper::{Body, Request, Response, StatusCode};
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+// How long the result of a test generation is reused for, so probes can't keep the model busy
+const GENERATION_CHECK_TTL: Duration = Duration::from_secs(30);
+
+// Struct to represent the liveness endpoint response
+#[derive(Serialize)]
+struct LiveResponse {
+    success: bool,
+    uptime_seconds: u64,
+}
+
+// Struct to represent the readiness endpoint response
+#[derive(Serialize)]
+struct ReadyResponse {
+    success: bool,
+    ready: bool,
+    uptime_seconds: u64,
+    models: Vec<ModelHealth>,
+    // The most recent test generation on the default model, if one was asked for
+    // and the model is loaded
+    generation: Option<GenerationCheck>,
+}
+
+// Struct to represent the health of a single model
+#[derive(Serialize)]
+struct ModelHealth {
+    name: String,
+    file_found: bool,
+    loaded: bool,
+    is_busy: bool,
+    queued: usize,
+    // Whether the queue is full, so new requests are turned away
+    saturated: bool,
+}
+
+// Struct to represent the result of a test generation
+#[derive(Serialize, Clone)]
+struct GenerationCheck {
+    success: bool,
+    elapsed_ms: u64,
+    error: Option<String>,
+    #[serde(skip)]
+    checked: Instant,
+}
+
+// What the health endpoints keep track of between requests
+pub struct Health {
+    started: Instant,
+    // Probes asking for a test generation at the same time wait for a single one
+    last_check: Mutex<Option<GenerationCheck>>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            last_check: Mutex::new(None),
+        }
+    }
+
+    fn uptime_seconds(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+}
+
+// Whether a path is one of the health checks
+pub fn is_health_check(path: &str) -> bool {
+    path == "/health/live" || path == "/health/ready"
+}
+
+// Responds as long as the server is able to handle requests at all
+pub async fn live_endpoint(state: &ServerState) -> Result<Response<Body>, LLMError> {
+    json_response(
+        StatusCode::OK,
+        &LiveResponse {
+            success: true,
+            uptime_seconds: state.health.uptime_seconds(),
+        },
+    )
+}
+
+// Responds with 200 if the default model can serve requests, or 503 if it can't:
+// its file is missing, its queue is full, or (with `?generate=true`) a test generation fails.
+// Health checks need no API key, so the test generation never loads the model.
+pub async fn ready_endpoint(
+    req: Request<Body>,
+    state: &ServerState,
+) -> Result<Response<Body>, LLMError> {
+    let generate = req
+        .uri()
+        .query()
+        .map(|query| {
+            query
+                .split('&')
+                .any(|param| matches!(param, "generate" | "generate=true" | "generate=1"))
+        })
+        .unwrap_or(false);
+
+    let models: Vec<ModelHealth> = state
+        .models
+        .iter()
+        .map(|model| ModelHealth {
+            name: model.name.clone(),
+            file_found: model_file_exists(&model.path()),
+            loaded: model.queue.is_loaded(),
+            is_busy: model.queue.is_busy(),
+            queued: model.queue.queued(),
+            saturated: model.queue.is_saturated(),
+        })
+        .collect();
+
+    let generation = match (generate, state.models.default_model()) {
+        (true, Some(model)) => generation_check(state, model).await,
+        _ => None,
+    };
+
+    // Models which aren't loaded from files (ie. mocks) don't need to find one
+    let needs_file = state
+        .models
+        .default_model()
+        .map(|model| model.settings.backend.uses_model_files())
+        .unwrap_or(true);
+    let ready = models
+        .first()
+        .map(|model| (model.file_found || !needs_file) && !model.saturated)
+        .unwrap_or(false)
+        && generation.as_ref().map(|g| g.success).unwrap_or(true);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    json_response(
+        status,
+        &ReadyResponse {
+            success: true,
+            ready,
+            uptime_seconds: state.health.uptime_seconds(),
+            models,
+            generation,
+        },
+    )
+}
+
+// Runs a test generation on the model, unless one was run recently.
+// Skipped while the model isn't loaded, so probes neither load it (which could unload other
+// models to stay within the memory budget) nor keep it from being unloaded once idle.
+async fn generation_check(state: &ServerState, model: &Model) -> Option<GenerationCheck> {
+    let mut last_check = state.health.last_check.lock().await;
+    if let Some(check) = last_check.as_ref() {
+        if check.checked.elapsed() < GENERATION_CHECK_TTL {
+            return Some(check.clone());
+        }
+    }
+
+    // The test generation takes its turn in the queue like any other request.
+    // It doesn't count towards the metrics, and leaves the cached prompt for the next request.
+    let started = Instant::now();
+    let result = match model.queue.acquire_if_loaded().await {
+        Ok(None) => return None,
+        Ok(Some(mut llm)) => {
+            tokio::task::block_in_place(|| llm.check_generation().map_err(|e| e.to_string()))
+        }
+        Err(e) => Err(e.to_string()),
+    };
+
+    let check = GenerationCheck {
+        success: result.is_ok(),
+        elapsed_ms: started.elapsed().as_millis() as u64,
+        error: result.err(),
+        checked: Instant::now(),
+    };
+    *last_check = Some(check.clone());
+    Some(check)
+}
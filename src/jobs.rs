@@ -0,0 +1,320 @@
+use crate::cancel::CancelToken;
+use crate::endpoints::{model_forbidden_response, model_not_found_response, PromptInput};
+use crate::error::LLMError;
+use crate::keyring::ApiKey;
+use crate::llm_interface::take_generation_stats;
+use crate::ratelimit::Client;
+use crate::registry::Usage;
+use crate::responses::{error_response, json_response};
+use crate::sampling::Sampling;
+use crate::state::ServerState;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use hyper::body::to_bytes;
+use hyper::{Body, Request, Response};
+use hyper::{Method, StatusCode};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::AbortHandle;
+use tracing::Instrument;
+use uuid::Uuid;
+
+// The lifecycle states of a job
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    fn is_finished(self) -> bool {
+        matches!(
+            self,
+            JobStatus::Done | JobStatus::Failed | JobStatus::Cancelled
+        )
+    }
+}
+
+// A prompt submitted through the jobs API, and everything it has produced so far
+struct Job {
+    status: JobStatus,
+    output: String,
+    response: Option<String>,
+    error: Option<String>,
+    finished_at: Option<Instant>,
+    handle: Option<AbortHandle>,
+    // Stops the generation once the job is running
+    cancel: CancelToken,
+}
+
+// Struct to represent the status of a job
+#[derive(Serialize)]
+struct JobResponse {
+    success: bool,
+    job_id: String,
+    status: JobStatus,
+    // The tokens generated so far
+    output: String,
+    // The final result, once the job is done
+    response: Option<String>,
+    error: Option<String>,
+}
+
+// Holds every job, until `ttl` has passed since it finished
+pub struct JobStore {
+    jobs: std::sync::Mutex<HashMap<String, Job>>,
+    ttl: Duration,
+}
+
+impl JobStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            jobs: std::sync::Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    // Add a new queued job and return its id, dropping any expired jobs along the way
+    fn insert(&self) -> String {
+        let id = Uuid::new_v4().simple().to_string();
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, job| match job.finished_at {
+            Some(finished_at) => finished_at.elapsed() < self.ttl,
+            None => true,
+        });
+        jobs.insert(
+            id.clone(),
+            Job {
+                status: JobStatus::Queued,
+                output: String::new(),
+                response: None,
+                error: None,
+                finished_at: None,
+                handle: None,
+                cancel: CancelToken::new(),
+            },
+        );
+        id
+    }
+
+    fn set_handle(&self, id: &str, handle: AbortHandle) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            job.handle = Some(handle);
+        }
+    }
+
+    // Mark a queued job as running and return its cancel token,
+    // or None if it should not run anymore
+    fn start(&self, id: &str) -> Option<CancelToken> {
+        match self.jobs.lock().unwrap().get_mut(id) {
+            Some(job) if job.status == JobStatus::Queued => {
+                job.status = JobStatus::Running;
+                Some(job.cancel.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn append_output(&self, id: &str, token: &str) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            job.output.push_str(token);
+        }
+    }
+
+    // Record the result of a job, unless it was cancelled in the meantime
+    fn finish(&self, id: &str, result: Result<String, LLMError>) {
+        if let Some(job) = self.jobs.lock().unwrap().get_mut(id) {
+            if job.status.is_finished() {
+                return;
+            }
+            match result {
+                Ok(response) => {
+                    job.status = JobStatus::Done;
+                    job.response = Some(response);
+                }
+                Err(error) => {
+                    job.status = JobStatus::Failed;
+                    job.error = Some(error.to_string());
+                }
+            }
+            job.finished_at = Some(Instant::now());
+            job.handle = None;
+        }
+    }
+
+    // Cancel a job which has not finished yet
+    fn cancel(&self, id: &str) -> Result<JobResponse, (StatusCode, &'static str)> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs
+            .get_mut(id)
+            .ok_or((StatusCode::NOT_FOUND, "Job not found"))?;
+        if job.status.is_finished() {
+            return Err((StatusCode::CONFLICT, "Job has already finished"));
+        }
+        job.status = JobStatus::Cancelled;
+        job.finished_at = Some(Instant::now());
+        // Stop a running generation, releasing the LLM for the next request
+        job.cancel.cancel();
+        if let Some(handle) = job.handle.take() {
+            handle.abort();
+        }
+        Ok(Self::response(id, job))
+    }
+
+    fn get(&self, id: &str) -> Option<JobResponse> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|job| Self::response(id, job))
+    }
+
+    fn response(id: &str, job: &Job) -> JobResponse {
+        JobResponse {
+            success: true,
+            job_id: id.to_string(),
+            status: job.status,
+            output: job.output.clone(),
+            response: job.response.clone(),
+            error: job.error.clone(),
+        }
+    }
+}
+
+// Submit a prompt as a job, responding right away with the job's id
+pub async fn submit_job_endpoint(
+    mut req: Request<Body>,
+    state: Arc<ServerState>,
+) -> Result<Response<Body>, LLMError> {
+    if req.method() != Method::POST {
+        return error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+    }
+
+    // Extract the body from the request and deserialize it into a PromptInput struct
+    let body_bytes = to_bytes(req.body_mut()).await.unwrap_or_default();
+    let input: PromptInput = match serde_json::from_slice(&body_bytes) {
+        Ok(input) => input,
+        Err(_) => return error_response(StatusCode::BAD_REQUEST, "Failed to parse request body"),
+    };
+
+    let model = match state.models.get(input.model.as_deref()) {
+        Some(model) => model,
+        None => {
+            return model_not_found_response(req.uri().path(), &input.model.unwrap_or_default())
+        }
+    };
+    if let Some(key) = req.extensions().get::<Arc<ApiKey>>() {
+        if !key.allows_model(&model.name) {
+            return model_forbidden_response(req.uri().path(), &model.name);
+        }
+    }
+
+    // Invalid parameters are turned away right away, rather than failing the job once queued
+    let sampling = match model.sampling(&input.sampling) {
+        Ok(sampling) => sampling,
+        Err(error) => return error_response(StatusCode::BAD_REQUEST, &error.to_string()),
+    };
+
+    // Run the job in the background, taking its turn in the queue like any other request
+    let client = req.extensions().get::<Arc<Client>>().cloned();
+    let id = state.jobs.insert();
+    // The job keeps logging under the id of the request which submitted it
+    let job = run_job(Arc::clone(&state), id.clone(), input, sampling, client);
+    let task = tokio::spawn(job.in_current_span());
+    state.jobs.set_handle(&id, task.abort_handle());
+
+    match state.jobs.get(&id) {
+        Some(job) => json_response(StatusCode::ACCEPTED, &job),
+        None => error_response(StatusCode::INTERNAL_SERVER_ERROR, "Failed to create job"),
+    }
+}
+
+// Get the status of a job (GET), or cancel it (DELETE)
+pub async fn job_endpoint(
+    req: Request<Body>,
+    state: &ServerState,
+) -> Result<Response<Body>, LLMError> {
+    let id = req.uri().path().trim_start_matches("/jobs/");
+    match *req.method() {
+        Method::GET => match state.jobs.get(id) {
+            Some(job) => json_response(StatusCode::OK, &job),
+            None => error_response(StatusCode::NOT_FOUND, "Job not found"),
+        },
+        Method::DELETE => match state.jobs.cancel(id) {
+            Ok(job) => json_response(StatusCode::OK, &job),
+            Err((status, message)) => error_response(status, message),
+        },
+        _ => error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"),
+    }
+}
+
+// Waits for the job's turn on the LLM, then runs it while recording its output
+async fn run_job(
+    state: Arc<ServerState>,
+    id: String,
+    input: PromptInput,
+    sampling: Sampling,
+    client: Option<Arc<Client>>,
+) {
+    let model = match state.models.get(input.model.as_deref()) {
+        Some(model) => model,
+        None => {
+            return state.jobs.finish(
+                &id,
+                Err(LLMError::Custom("Model is not available".to_string())),
+            )
+        }
+    };
+    let mut llm_guard = match state.models.acquire(model, Usage::Generation).await {
+        Ok(llm_guard) => llm_guard,
+        Err(error) => {
+            return state
+                .jobs
+                .finish(&id, Err(LLMError::Custom(error.to_string())))
+        }
+    };
+    let cancel = match state.jobs.start(&id) {
+        Some(cancel) => cancel,
+        None => return,
+    };
+    // Collect the tokens into the job's partial output as they are generated
+    let (token_tx, mut token_rx) = mpsc::unbounded::<String>();
+    let collector = {
+        let state = Arc::clone(&state);
+        let id = id.clone();
+        tokio::spawn(async move {
+            while let Some(token) = token_rx.next().await {
+                state.jobs.append_output(&id, &token);
+            }
+        })
+    };
+
+    // Run the prompt on the current thread, as the rest of the endpoints do
+    let res = tokio::task::block_in_place(|| {
+        let _cancel = cancel.install();
+        take_generation_stats();
+        let res = futures::executor::block_on(llm_guard.submit_prompt_streaming(
+            &input.prompt,
+            &sampling,
+            token_tx,
+        ));
+        // Count what was generated against the client's daily quota
+        let stats = take_generation_stats();
+        state.metrics.record_generation(&stats);
+        if let Some(client) = &client {
+            client.record_tokens(stats.completion_tokens);
+        }
+        res
+    });
+    drop(llm_guard);
+
+    // Make sure the partial output is complete before the job is marked as done
+    let _ = collector.await;
+    state.jobs.finish(&id, res);
+}
@@ -0,0 +1,180 @@
+use crate::fs_reading::load_toml_or_yaml;
+use crate::ratelimit::RateLimits;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+
+// Struct to represent the keys file given with `--keys_file`
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct KeysFile {
+    keys: Vec<KeyEntry>,
+}
+
+// Struct to represent a single key of the keys file
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct KeyEntry {
+    name: String,
+    // Hex encoded SHA-256 hash of the key, as printed by the `hash-key` command
+    key_hash: String,
+    // Paths the key may use (a trailing `*` matches any suffix), every path if not given
+    endpoints: Option<Vec<String>>,
+    // Models the key may use, every model if not given
+    models: Option<Vec<String>>,
+    requests_per_minute: Option<u32>,
+    tokens_per_day: Option<u64>,
+    // Whether the key may use the `/admin/*` endpoints, which no other key may
+    admin: Option<bool>,
+}
+
+// An API key, with what it may access and how much
+pub struct ApiKey {
+    pub name: String,
+    hash: [u8; 32],
+    endpoints: Option<Vec<String>>,
+    models: Option<Vec<String>>,
+    // Limits which replace the server's default ones
+    pub limits: RateLimits,
+    pub admin: bool,
+}
+
+impl ApiKey {
+    // A key which may use everything, with the server's default limits
+    fn unrestricted(name: &str, key: &str) -> Self {
+        Self::new(
+            name.to_string(),
+            digest(key),
+            None,
+            None,
+            RateLimits::default(),
+            true,
+        )
+    }
+
+    fn new(
+        name: String,
+        hash: [u8; 32],
+        endpoints: Option<Vec<String>>,
+        models: Option<Vec<String>>,
+        limits: RateLimits,
+        admin: bool,
+    ) -> Self {
+        Self {
+            name,
+            hash,
+            endpoints,
+            models,
+            limits,
+            admin,
+        }
+    }
+
+    pub fn allows_endpoint(&self, path: &str) -> bool {
+        if path.starts_with("/admin/") && !self.admin {
+            return false;
+        }
+        match &self.endpoints {
+            Some(endpoints) => endpoints
+                .iter()
+                .any(|pattern| match pattern.strip_suffix('*') {
+                    Some(prefix) => path.starts_with(prefix),
+                    None => path == pattern,
+                }),
+            None => true,
+        }
+    }
+
+    pub fn allows_model(&self, model: &str) -> bool {
+        match &self.models {
+            Some(models) => models.iter().any(|m| m == model),
+            None => true,
+        }
+    }
+}
+
+// Every key which may access the server
+pub struct Keyring {
+    keys: Vec<Arc<ApiKey>>,
+}
+
+impl Keyring {
+    pub fn new() -> Self {
+        Self { keys: Vec::new() }
+    }
+
+    // Load the keys from the keys file
+    pub fn load(path: &str) -> Result<Self, String> {
+        let file: KeysFile = load_toml_or_yaml(path, "keys file")?;
+
+        let mut keyring = Self::new();
+        for entry in file.keys {
+            let hash = parse_hash(&entry.key_hash).ok_or_else(|| {
+                format!(
+                    "Invalid keys file {}: `key_hash` of key `{}` is not a hex encoded SHA-256 hash",
+                    path, entry.name
+                )
+            })?;
+            keyring.add(ApiKey::new(
+                entry.name,
+                hash,
+                entry.endpoints,
+                entry.models,
+                RateLimits {
+                    requests_per_minute: entry.requests_per_minute,
+                    tokens_per_day: entry.tokens_per_day,
+                },
+                entry.admin.unwrap_or(false),
+            ))?;
+        }
+        Ok(keyring)
+    }
+
+    // Add a key which may use everything, ie. the one given with `--api-key` (named `default`)
+    pub fn add_unrestricted(&mut self, name: &str, key: &str) -> Result<(), String> {
+        self.add(ApiKey::unrestricted(name, key))
+    }
+
+    fn add(&mut self, key: ApiKey) -> Result<(), String> {
+        if self.keys.iter().any(|k| k.name == key.name) {
+            return Err(format!("Key name `{}` is used more than once", key.name));
+        }
+        self.keys.push(Arc::new(key));
+        Ok(())
+    }
+
+    // Find the key matching the one a client provided.
+    // Every key is compared in constant time, so the timing reveals neither the key nor which one matched.
+    pub fn authenticate(&self, provided: &str) -> Option<Arc<ApiKey>> {
+        let hash = digest(provided);
+        let mut found = None;
+        for key in &self.keys {
+            if bool::from(key.hash.ct_eq(&hash)) {
+                found = Some(Arc::clone(key));
+            }
+        }
+        found
+    }
+}
+
+// The hex encoded SHA-256 hash of a key, as stored in the keys file
+pub fn hash_key(key: &str) -> String {
+    digest(key).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn digest(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+fn parse_hash(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut hash = [0u8; 32];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(hash)
+}
@@ -1,73 +1,232 @@
+use crate::cancel;
+use crate::embeddings::EmbeddingContext;
 use crate::error::LLMError;
-use llm_chain::{prompt, traits::Executor, Parameters};
-use llm_chain_llama::Executor as LlamaExecutor;
-use llm_chain_llama::{PerExecutor, PerInvocation};
+use crate::prompt_cache::PromptCache;
+use crate::registry::model_name_from_path;
+use crate::sampling::Sampling;
+use futures::channel::mpsc::UnboundedSender;
+use std::cell::Cell;
+use std::time::Instant;
+use tracing::{debug, error, info};
 
-pub struct LLMInterface<T: Executor> {
-    pub exec: T,
-    pub api_key: Option<String>,
+// Channel over which generated tokens are streamed back to the caller
+pub type TokenSender = UnboundedSender<String>;
+
+thread_local! {
+    // What was generated on this thread since `take_generation_stats` was last called
+    static GENERATION_STATS: Cell<GenerationStats> = const { Cell::new(GenerationStats::new()) };
+}
+
+// What a generation evaluated and produced, and how quickly
+#[derive(Debug, Clone, Copy)]
+pub struct GenerationStats {
+    pub prompt_tokens: usize,
+    // Prompt tokens whose evaluated state was reused from an earlier prompt
+    pub cached_tokens: usize,
+    pub completion_tokens: usize,
+    // When the prompt was submitted, and when the first and last tokens were generated
+    pub started: Option<Instant>,
+    pub first_token: Option<Instant>,
+    pub last_token: Option<Instant>,
+}
+
+impl GenerationStats {
+    const fn new() -> Self {
+        Self {
+            prompt_tokens: 0,
+            cached_tokens: 0,
+            completion_tokens: 0,
+            started: None,
+            first_token: None,
+            last_token: None,
+        }
+    }
+}
+
+// Returns what was generated on this thread since the last call, and resets it
+pub fn take_generation_stats() -> GenerationStats {
+    GENERATION_STATS.with(|stats| stats.replace(GenerationStats::new()))
+}
+
+// Records a prompt being submitted on this thread
+pub fn record_prompt(prompt_tokens: usize) {
+    GENERATION_STATS.with(|stats| {
+        stats.set(GenerationStats {
+            prompt_tokens: stats.get().prompt_tokens + prompt_tokens,
+            started: Some(Instant::now()),
+            ..stats.get()
+        })
+    });
+}
+
+// Records how many tokens of the prompt just submitted on this thread were cached
+pub fn record_cached_tokens(cached_tokens: usize) {
+    GENERATION_STATS.with(|stats| {
+        stats.set(GenerationStats {
+            cached_tokens: stats.get().cached_tokens + cached_tokens,
+            ..stats.get()
+        })
+    });
 }
-impl LLMInterface<LlamaExecutor> {
+
+// Records a token being generated on this thread
+pub fn record_token() {
+    GENERATION_STATS.with(|stats| {
+        let mut current = stats.get();
+        let now = Instant::now();
+        current.completion_tokens += 1;
+        current.first_token.get_or_insert(now);
+        current.last_token = Some(now);
+        stats.set(current);
+    });
+}
+
+pub struct LLMInterface {
+    // Keeps the evaluated state of the last prompt, for the next one to reuse
+    pub context: PromptCache,
+    // The name requests select the model by
+    pub name: String,
+    pub model_path: String,
+    pub num_threads: u16,
+    // Whether prompt text is written to the log
+    pub log_prompts: bool,
+    // Loaded on the first embeddings request, through the registry so it counts against the budget
+    pub embeddings: Option<EmbeddingContext>,
+}
+impl LLMInterface {
     // Create a new local LLM instance with the given parameters
+    // (sampling options are resolved per request, see `Model::sampling`)
     pub fn new_local_llm(
-        model_path: &str,      // Path to the model
-        num_threads: u16,      // Number of threads to use
-        temp: f32,             // Temperature for sampling
-        freq_penalty: f32,     // Frequency penalty for sampling
-        output_tokens: usize,  // Number of tokens to predict
-        api_key: Option<&str>, // Optional api key
+        model_path: &str, // Path to the model
+        num_threads: u16, // Number of threads to use
     ) -> Result<Self, LLMError> {
-        // Setup all options
-        let exec_options = PerExecutor::new().with_model_path(model_path);
-        let mut inv_options = PerInvocation::new();
-        inv_options.n_threads = Some(num_threads as i32);
-        inv_options.temp = Some(temp);
-        inv_options.repeat_penalty = Some(freq_penalty);
-        inv_options.n_tok_predict = Some(output_tokens);
-
-        let executor = LlamaExecutor::new_with_options(Some(exec_options), Some(inv_options))
-            .map_err(|_| LLMError::InitializingLLMFailed);
-
-        // Looks like the error might not be propagating to here?
-        if let Err(e) = executor {
-            println!("Failed to initialize LLM interface: {}", e);
-            std::process::exit(1);
+        let context = PromptCache::new(model_path, num_threads);
+
+        // Models are loaded on demand while the server is running, so failing must not exit
+        if let Err(e) = &context {
+            error!(path = model_path, error = %e, "Failed to initialize LLM interface");
         }
 
         Ok(Self {
-            exec: executor?,
-            api_key: api_key.map(|s| s.to_string()),
+            context: context?,
+            name: model_name_from_path(model_path),
+            model_path: model_path.to_string(),
+            num_threads,
+            log_prompts: true,
+            embeddings: None,
         })
     }
 
-    // Submit a prompt to the LLM if it isn't currently busy
-    pub async fn submit_prompt(&mut self, prompt_text: &str) -> Result<String, LLMError> {
-        println!("Prompt received: {}", prompt_text);
-        // Run prompt
-        let params = Parameters::new();
-        let res = prompt!(prompt_text)
-            .run(&params, &self.exec)
-            .await
-            .map_err(|_| LLMError::SubmittingPromptFailed)?;
-        // Acquire result string
-        let res_string = res.to_string();
+    // The name of the loaded model
+    pub fn model_name(&self) -> String {
+        self.name.clone()
+    }
+
+    // Count the number of tokens the given text is made up of
+    pub fn count_tokens(&self, text: &str) -> Result<usize, LLMError> {
+        let tokens = self.context.tokenize(text)?;
+        // The tokenizer always prepends the beginning-of-sentence token
+        Ok(tokens.len().saturating_sub(1))
+    }
+
+    // The number of tokens the llama.cpp context holds
+    pub fn context_size(&self) -> usize {
+        self.context.context_size()
+    }
+
+    // Submit a prompt to the LLM if it isn't currently busy.
+    // Fails with `LLMError::Cancelled` if the generation is cancelled while running.
+    pub async fn submit_prompt(
+        &mut self,
+        prompt_text: &str,
+        sampling: &Sampling,
+    ) -> Result<String, LLMError> {
+        self.generate(prompt_text, sampling, None)
+    }
+
+    // Submit a prompt to the LLM, sending each token through `token_tx` as it is generated.
+    // The full response is still returned once generation completes.
+    pub async fn submit_prompt_streaming(
+        &mut self,
+        prompt_text: &str,
+        sampling: &Sampling,
+        token_tx: TokenSender,
+    ) -> Result<String, LLMError> {
+        self.generate(prompt_text, sampling, Some(token_tx))
+    }
+
+    fn generate(
+        &mut self,
+        prompt_text: &str,
+        sampling: &Sampling,
+        token_tx: Option<TokenSender>,
+    ) -> Result<String, LLMError> {
+        // Prompt text is only ever logged if enabled, and then only at the debug level
+        if self.log_prompts {
+            debug!(model = %self.name, prompt = prompt_text, "Prompt received");
+        } else {
+            debug!(model = %self.name, "Prompt received");
+        }
+        // Don't bother evaluating the prompt if the request was cancelled while queued
+        if cancel::current_is_cancelled() {
+            return Err(LLMError::Cancelled);
+        }
+        if let Some(seed) = sampling.seed {
+            self.context.set_seed(seed);
+        }
+        record_prompt(self.count_tokens(prompt_text).unwrap_or(0));
+        let cached_tokens = self.context.evaluate(prompt_text)?;
+        record_cached_tokens(cached_tokens);
+        debug!(model = %self.name, cached_tokens, "Prompt evaluated");
+
+        // Stream every token, and stop if cancelled or the receiver went away (ie. client disconnect)
+        let res = self.context.generate(sampling, |token| {
+            record_token();
+            let delivered = match &token_tx {
+                Some(tx) => tx.unbounded_send(token.to_string()).is_ok(),
+                None => true,
+            };
+            if !delivered || cancel::current_is_cancelled() {
+                return Err(LLMError::Cancelled);
+            }
+            Ok(())
+        });
+        let mut res_string = match res {
+            Ok(res_string) => res_string,
+            Err(LLMError::Cancelled) => {
+                info!(model = %self.name, "Prompt cancelled");
+                return Err(LLMError::Cancelled);
+            }
+            Err(error) => return Err(error),
+        };
+        sampling.apply_stop_sequences(&mut res_string);
 
         // Return string
-        return Ok(res_string);
+        Ok(res_string)
     }
 
-    // // Generate embeddings for the given input
-    // pub async fn generate_embeddings(&mut self, input_text: &str) -> Result<Vec<i32>, LLMError> {
-    //     println!("Generating embeddings for: {}", input_text);
-    //     // Run prompt
-    //     let res = self.exec.generate_embeddings(input_text);
+    // Check that the model still generates, keeping the cached prompt for the next request
+    pub fn check_generation(&mut self) -> Result<(), LLMError> {
+        self.context.probe("Hello")
+    }
 
-    //     println!("Embedding Vector:");
-    //     for element in &res {
-    //         println!("{}", element);
-    //     }
+    // Load the model in embedding mode, if it isn't already
+    pub fn load_embeddings(&mut self) -> Result<(), LLMError> {
+        if self.embeddings.is_none() {
+            self.embeddings = Some(EmbeddingContext::new(&self.model_path, self.num_threads)?);
+        }
+        Ok(())
+    }
 
-    //     // Return string
-    //     return Ok(res);
-    // }
+    // Generate the embedding vector for the given input
+    pub async fn generate_embeddings(&mut self, input_text: &str) -> Result<Vec<f32>, LLMError> {
+        // A single embedding can't be interrupted, but a cancelled batch stops between inputs
+        if cancel::current_is_cancelled() {
+            return Err(LLMError::Cancelled);
+        }
+        match self.embeddings.as_mut() {
+            Some(embeddings) => embeddings.embed(input_text),
+            None => Err(LLMError::InitializingLLMFailed),
+        }
+    }
 }
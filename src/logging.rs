@@ -0,0 +1,93 @@
+use crate::error::LLMError;
+use crate::metrics::metrics_layer;
+use crate::state::ServerState;
+use hyper::header::HeaderValue;
+use hyper::{Body, Request, Response};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{error, info, info_span, Instrument};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+// Header holding the id a request is logged (and its generation cancelled) with
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+// The id of a request, included in every log line written while handling it
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+// Sets up logging at the given level (or `tracing` filter directives such as
+// `open_llm_server=debug`), as plain text or one JSON object per line.
+// Logs go to stdout, or to stderr when stdout is taken by other output (ie. `--dry-run`).
+pub fn init_logging(level: &str, json: bool, to_stderr: bool) {
+    let filter = EnvFilter::try_new(level).unwrap_or_else(|_| EnvFilter::new("info"));
+    let writer = if to_stderr {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer);
+    if json {
+        builder.json().init();
+    } else {
+        builder.init();
+    }
+}
+
+// Checks that a log level is one `init_logging` accepts
+pub fn check_log_level(level: &str) -> Result<String, String> {
+    EnvFilter::try_new(level)
+        .map(|_| level.to_string())
+        .map_err(|e| format!("is not a valid log level: {}", e))
+}
+
+// Gives every request an id, using the client's if it sent a usable one,
+// and logs the request once it has been handled
+pub async fn logging_layer(
+    mut req: Request<Body>,
+    state: Arc<ServerState>,
+    remote_addr: SocketAddr,
+) -> Result<Response<Body>, LLMError> {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(|id| id.to_string())
+        .unwrap_or_else(|| Uuid::new_v4().simple().to_string());
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+
+    let span = info_span!(
+        "request",
+        id = %request_id,
+        method = %req.method(),
+        path = %req.uri().path(),
+        client = %remote_addr.ip(),
+    );
+    async move {
+        let started = Instant::now();
+        let res = metrics_layer(req, state, remote_addr).await;
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        match &res {
+            Ok(res) => info!(
+                status = res.status().as_u16(),
+                elapsed_ms, "Request handled"
+            ),
+            Err(e) => error!(error = %e, elapsed_ms, "Request failed"),
+        }
+
+        // Let the client know which id to find the request (or cancel its generation) by
+        res.map(|mut res| {
+            if let Ok(value) = HeaderValue::from_str(&request_id) {
+                res.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+            res
+        })
+    }
+    .instrument(span)
+    .await
+}
@@ -1,18 +1,66 @@
+mod admin;
+mod auth;
+mod backend;
+mod cancel;
 mod cli;
+mod config;
+mod embeddings;
 mod endpoints;
 mod error;
 mod fs_reading;
+mod health;
+mod jobs;
+mod keyring;
 mod llm_interface;
+mod logging;
+mod metrics;
+mod mock;
+mod openai;
+mod prompt_cache;
+mod queue;
+mod ratelimit;
+mod registry;
+mod responses;
+mod sampling;
+mod sessions;
+mod state;
+mod templates;
+#[cfg(test)]
+mod tests;
+mod tls;
 
+use backend::BackendKind;
+use cancel::Generations;
+use clap::ValueSource;
 use cli::cli_interface;
-use endpoints::route_requests;
-use fs_reading::{find_local_model, model_file_close_check};
+use config::{
+    load_config, ConfigFile, ConfigModel, LimitsConfig, LoggingConfig, MockConfig, SamplingConfig,
+};
+use fs_reading::{find_local_model, model_file_close_check, model_file_exists, read_api_key_file};
+use health::Health;
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::Server;
-use llm_interface::LLMInterface;
+use jobs::JobStore;
+use keyring::{hash_key, Keyring};
+use logging::{init_logging, logging_layer};
+use metrics::Metrics;
+use mock::{load_script, MockSettings, DEFAULT_CONTEXT_SIZE};
+use queue::RequestQueue;
+use ratelimit::{RateLimiter, RateLimits};
+use registry::{Model, ModelRegistry, ModelSettings, ModelSpec};
+use sampling::SamplingLimits;
+use sessions::SessionStore;
+use state::ServerState;
+use std::collections::HashMap;
 use std::error::Error;
+use std::net::{IpAddr, SocketAddr, TcpListener};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use templates::{Templates, DEFAULT_TEMPLATE};
+use tls::{load_tls_acceptor, serve_tls};
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info};
 
 pub const APP_VERSION: &str = "0.1.0";
 
@@ -21,101 +69,546 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let matches = cli_interface(); // Get the command line interface arguments
     match matches.subcommand() {
         Some(("run", sub_m)) => return handle_run_command(sub_m).await, // If the subcommand is "run" then call the handle_run_command function
-        Some(("help", _)) => println!(""),
+        Some(("hash-key", sub_m)) => handle_hash_key_command(sub_m)?,
+        Some(("help", _)) => println!(),
         _ => println!("Open LLM Server\nInvalid Command"), // Otherwise print an invalid command message
     }
     Ok(()) // Return Ok if no errors occur
 }
 
+// The API key, which can also be read from a file to keep it out of the process list.
+// Flags win over environment variables, which win over the config file, and at each of
+// those the key itself wins over a key file.
+fn resolve_api_key(sub_m: &clap::ArgMatches, config: &ConfigFile) -> Option<String> {
+    let from = |source: ValueSource| {
+        if sub_m.value_source("api_key") == Some(source) {
+            sub_m.value_of("api_key").map(str::to_string)
+        } else if sub_m.value_source("api_key_file") == Some(source) {
+            sub_m.value_of("api_key_file").map(read_api_key_file)
+        } else {
+            None
+        }
+    };
+    from(ValueSource::CommandLine)
+        .or_else(|| from(ValueSource::EnvVariable))
+        .or_else(|| config.api_key.clone())
+        .or_else(|| config.api_key_file.as_deref().map(read_api_key_file))
+}
+
+// Print the hash of an API key, as stored in the keys file
+fn handle_hash_key_command(sub_m: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
+    // Reading the key from stdin keeps it out of the shell history
+    let key = match sub_m.value_of("key") {
+        Some(key) => key.to_string(),
+        None => {
+            let mut key = String::new();
+            std::io::stdin().read_line(&mut key)?;
+            key.trim().to_string()
+        }
+    };
+    if key.is_empty() {
+        eprintln!("Error: No API key given.");
+        std::process::exit(1);
+    }
+    println!("{}", hash_key(&key));
+    Ok(())
+}
+
 // Handle input parsing and starting webserver
 async fn handle_run_command(sub_m: &clap::ArgMatches) -> Result<(), Box<dyn Error>> {
-    let default_port = 8080;
-    let default_threads = 8;
-    let default_temp = 0.7;
-    let default_freq_penalty = 1.2;
-    let default_output_tokens = 2048;
+    // Settings from the config file (if any) replace the built-in defaults,
+    // and are in turn overridden by environment variables and command line flags.
+    // Flags and environment variables are validated by clap, exiting with an error if invalid.
+    let config = match sub_m.value_of("config") {
+        Some(path) => match load_config(path) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => ConfigFile::default(),
+    };
+    let limits_config = &config.limits;
 
+    // Set up logging first, so everything after can be logged
+    let log_level = sub_m
+        .value_of("log_level")
+        .or(config.logging.level.as_deref())
+        .unwrap_or("info")
+        .to_string();
+    let log_format = sub_m
+        .value_of("log_format")
+        .or(config.logging.format.as_deref())
+        .unwrap_or("text")
+        .to_string();
+    // A dry run prints the configuration to stdout, so its startup errors are logged to stderr
+    init_logging(
+        &log_level,
+        log_format == "json",
+        sub_m.is_present("dry_run"),
+    );
+
+    let default_port = config.port.unwrap_or(8080);
+    let default_threads = config.num_threads.unwrap_or(8);
+    let default_temp = config.sampling.temp.unwrap_or(0.7);
+    let default_freq_penalty = config.sampling.freq_penalty.unwrap_or(1.2);
+    let default_output_tokens = config.sampling.output_tokens.unwrap_or(2048);
+    let default_max_temp = limits_config.max_temp.unwrap_or(2.0);
+    let default_max_top_k = limits_config.max_top_k.unwrap_or(100);
+    let default_max_repeat_penalty = limits_config.max_repeat_penalty.unwrap_or(2.0);
+    let default_max_output_tokens = limits_config.max_output_tokens.unwrap_or(4096);
+    let default_max_stop_sequences = limits_config.max_stop_sequences.unwrap_or(4);
+    let default_queue_depth = limits_config.queue_depth.unwrap_or(16);
+    let default_queue_timeout = limits_config.queue_timeout.unwrap_or(300);
+    let default_job_ttl = limits_config.job_ttl.unwrap_or(3600);
+    let default_session_ttl = limits_config.session_ttl.unwrap_or(3600);
+    let default_memory_budget = limits_config.memory_budget.unwrap_or(0);
+    let default_model_idle_timeout = limits_config.model_idle_timeout.unwrap_or(0);
+    let default_requests_per_minute = limits_config.requests_per_minute.unwrap_or(0);
+    let default_tokens_per_day = limits_config.tokens_per_day.unwrap_or(0);
+    let default_host = config.host.unwrap_or(IpAddr::from([127, 0, 0, 1]));
+    let default_log_prompts = config.logging.log_prompts.unwrap_or(false);
+    let default_mock_latency_ms = config.mock.latency_ms.unwrap_or(0);
+    let default_mock_tokens_per_second = config.mock.tokens_per_second.unwrap_or(0.0);
+    let default_mock_context_size = config.mock.context_size.unwrap_or(DEFAULT_CONTEXT_SIZE);
+
+    let host = sub_m
+        .get_one::<IpAddr>("host")
+        .copied()
+        .unwrap_or(default_host);
     let port = sub_m
-        .value_of("port")
-        .unwrap_or(&default_port.to_string())
-        .parse::<u16>()
+        .get_one::<u16>("port")
+        .copied()
         .unwrap_or(default_port);
     let num_threads = sub_m
-        .value_of("num_threads")
-        .unwrap_or(&default_threads.to_string())
-        .parse::<u16>()
+        .get_one::<u16>("num_threads")
+        .copied()
         .unwrap_or(default_threads);
     let temp = sub_m
-        .value_of("temp")
-        .unwrap_or(&default_temp.to_string())
-        .parse::<f32>()
+        .get_one::<f32>("temp")
+        .copied()
         .unwrap_or(default_temp);
     let freq_penalty = sub_m
-        .value_of("freq_penalty")
-        .unwrap_or(&default_freq_penalty.to_string())
-        .parse::<f32>()
+        .get_one::<f32>("freq_penalty")
+        .copied()
         .unwrap_or(default_freq_penalty);
     let output_tokens = sub_m
-        .value_of("output_tokens")
-        .unwrap_or(&default_output_tokens.to_string())
-        .parse::<usize>()
+        .get_one::<usize>("output_tokens")
+        .copied()
         .unwrap_or(default_output_tokens);
-    let api_key = sub_m.value_of("api_key");
-    let m_arg = sub_m.value_of("model");
-    let model_path = match m_arg {
-        Some(m) => m.to_string(),
-        None => find_local_model().unwrap_or(("model.bin").to_string()),
+    let limits = SamplingLimits {
+        max_temp: sub_m
+            .get_one::<f32>("max_temp")
+            .copied()
+            .unwrap_or(default_max_temp),
+        max_top_k: sub_m
+            .get_one::<i32>("max_top_k")
+            .copied()
+            .unwrap_or(default_max_top_k),
+        max_repeat_penalty: sub_m
+            .get_one::<f32>("max_repeat_penalty")
+            .copied()
+            .unwrap_or(default_max_repeat_penalty),
+        max_output_tokens: sub_m
+            .get_one::<usize>("max_output_tokens")
+            .copied()
+            .unwrap_or(default_max_output_tokens),
+        max_stop_sequences: sub_m
+            .get_one::<usize>("max_stop_sequences")
+            .copied()
+            .unwrap_or(default_max_stop_sequences),
+    };
+    let queue_depth = sub_m
+        .get_one::<usize>("queue_depth")
+        .copied()
+        .unwrap_or(default_queue_depth);
+    let queue_timeout = sub_m
+        .get_one::<u64>("queue_timeout")
+        .copied()
+        .unwrap_or(default_queue_timeout);
+    let job_ttl = sub_m
+        .get_one::<u64>("job_ttl")
+        .copied()
+        .unwrap_or(default_job_ttl);
+    let session_ttl = sub_m
+        .get_one::<u64>("session_ttl")
+        .copied()
+        .unwrap_or(default_session_ttl);
+    let memory_budget = sub_m
+        .get_one::<u64>("memory_budget")
+        .copied()
+        .unwrap_or(default_memory_budget);
+    let model_idle_timeout = sub_m
+        .get_one::<u64>("model_idle_timeout")
+        .copied()
+        .unwrap_or(default_model_idle_timeout);
+    let log_prompts = sub_m
+        .get_one::<bool>("log_prompts")
+        .copied()
+        .unwrap_or(default_log_prompts);
+    let requests_per_minute = sub_m
+        .get_one::<u32>("requests_per_minute")
+        .copied()
+        .unwrap_or(default_requests_per_minute);
+    let tokens_per_day = sub_m
+        .get_one::<u64>("tokens_per_day")
+        .copied()
+        .unwrap_or(default_tokens_per_day);
+    let backend = sub_m
+        .value_of("backend")
+        .or(config.backend.as_deref())
+        .unwrap_or("llama")
+        .to_string();
+    let mock_latency_ms = sub_m
+        .get_one::<u64>("mock_latency_ms")
+        .copied()
+        .unwrap_or(default_mock_latency_ms);
+    let mock_tokens_per_second = sub_m
+        .get_one::<f32>("mock_tokens_per_second")
+        .copied()
+        .unwrap_or(default_mock_tokens_per_second);
+    let mock_context_size = sub_m
+        .get_one::<usize>("mock_context_size")
+        .copied()
+        .unwrap_or(default_mock_context_size);
+    let mock_script = sub_m
+        .value_of("mock_script")
+        .or(config.mock.script.as_deref());
+    let api_key = resolve_api_key(sub_m, &config);
+    // Named keys with their own scopes and limits, which may be used alongside the API key
+    let keys_file = sub_m.value_of("keys_file").or(config.keys_file.as_deref());
+    // TLS is enabled by giving both a certificate and a private key
+    let tls_cert = sub_m.value_of("tls_cert").or(config.tls_cert.as_deref());
+    let tls_key = sub_m.value_of("tls_key").or(config.tls_key.as_deref());
+    if tls_cert.is_some() != tls_key.is_some() {
+        error!("A TLS certificate and private key must be given together.");
+        std::process::exit(1);
+    }
+    let mut models: Vec<ModelSpec> = match (sub_m.values_of("model"), &config.models) {
+        (Some(models), _) => models.map(ModelSpec::parse).collect(),
+        (None, Some(models)) if !models.is_empty() => models.iter().map(|m| m.spec()).collect(),
+        _ => vec![ModelSpec::parse(
+            &find_local_model().unwrap_or(("model.bin").to_string()),
+        )],
     };
 
-    model_file_close_check(&model_path);
-    return run_webserver(
-        &model_path,
-        port,
+    // The chat template of each model: one given for the model on the command line, or else the
+    // one given for all models there, or else the model's own from the config file, or else the
+    // config file's default
+    let templates_file = sub_m
+        .value_of("templates_file")
+        .or(config.templates_file.as_deref());
+    let mut template_flag = None;
+    let mut model_templates = HashMap::new();
+    for value in sub_m.values_of("chat_template").into_iter().flatten() {
+        match value.split_once('=') {
+            Some((model, template)) => {
+                model_templates.insert(model, template.to_string());
+            }
+            None => template_flag = Some(value.to_string()),
+        }
+    }
+    for name in model_templates.keys() {
+        if !models.iter().any(|model| model.name == *name) {
+            error!(
+                "A chat template was given for the model `{}`, which is not being served.",
+                name
+            );
+            std::process::exit(1);
+        }
+    }
+    let default_template = template_flag
+        .clone()
+        .or_else(|| config.chat_template.clone())
+        .unwrap_or_else(|| DEFAULT_TEMPLATE.to_string());
+    for model in &mut models {
+        let template = model_templates
+            .remove(model.name.as_str())
+            .or_else(|| template_flag.clone())
+            .or_else(|| model.template.take())
+            .unwrap_or_else(|| default_template.clone());
+        model.template = Some(template);
+    }
+    let templates = match templates_file {
+        Some(path) => Templates::load(path),
+        None => Ok(Templates::builtin()),
+    }
+    .and_then(|templates| {
+        for model in &models {
+            let template = model.template.as_deref().unwrap_or(DEFAULT_TEMPLATE);
+            templates
+                .get(template)
+                .map_err(|e| format!("Model `{}`: {}", model.name, e))?;
+        }
+        Ok(templates)
+    });
+
+    // Print the configuration the server would run with, in the config file format
+    if sub_m.is_present("dry_run") {
+        let resolved = ConfigFile {
+            host: Some(host),
+            port: Some(port),
+            api_key: api_key.as_ref().map(|_| "<redacted>".to_string()),
+            api_key_file: None,
+            keys_file: keys_file.map(|s| s.to_string()),
+            tls_cert: tls_cert.map(|s| s.to_string()),
+            tls_key: tls_key.map(|s| s.to_string()),
+            num_threads: Some(num_threads),
+            backend: Some(backend.clone()),
+            models: Some(models.iter().map(ConfigModel::from).collect()),
+            chat_template: Some(default_template),
+            templates_file: templates_file.map(|s| s.to_string()),
+            sampling: SamplingConfig {
+                temp: Some(temp),
+                freq_penalty: Some(freq_penalty),
+                output_tokens: Some(output_tokens),
+            },
+            limits: LimitsConfig {
+                max_temp: Some(limits.max_temp),
+                max_top_k: Some(limits.max_top_k),
+                max_repeat_penalty: Some(limits.max_repeat_penalty),
+                max_output_tokens: Some(limits.max_output_tokens),
+                max_stop_sequences: Some(limits.max_stop_sequences),
+                queue_depth: Some(queue_depth),
+                queue_timeout: Some(queue_timeout),
+                job_ttl: Some(job_ttl),
+                session_ttl: Some(session_ttl),
+                memory_budget: Some(memory_budget),
+                model_idle_timeout: Some(model_idle_timeout),
+                requests_per_minute: Some(requests_per_minute),
+                tokens_per_day: Some(tokens_per_day),
+            },
+            logging: LoggingConfig {
+                level: Some(log_level),
+                format: Some(log_format),
+                log_prompts: Some(log_prompts),
+            },
+            mock: MockConfig {
+                latency_ms: Some(mock_latency_ms),
+                tokens_per_second: Some(mock_tokens_per_second),
+                script: mock_script.map(|s| s.to_string()),
+                context_size: Some(mock_context_size),
+            },
+        };
+        print!("{}", toml::to_string(&resolved)?);
+        let uses_model_files = backend == "llama";
+        for model in models
+            .iter()
+            .filter(|m| uses_model_files && !model_file_exists(&m.path))
+        {
+            println!(
+                "# Warning: model file {} could not be found/read",
+                model.path
+            );
+        }
+        if let Some(Err(e)) = keys_file.map(Keyring::load) {
+            println!("# Warning: {}", e);
+        }
+        if let Some(Err(e)) = mock_script.map(load_script) {
+            println!("# Warning: {}", e);
+        }
+        if let Err(e) = templates {
+            println!("# Warning: {}", e);
+        }
+        return Ok(());
+    }
+
+    let templates = match templates {
+        Ok(templates) => templates,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let backend = match backend.as_str() {
+        "mock" => {
+            let script = match mock_script.map(load_script) {
+                Some(Ok(script)) => script,
+                Some(Err(e)) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+                None => Vec::new(),
+            };
+            BackendKind::Mock(MockSettings {
+                latency: Duration::from_millis(mock_latency_ms),
+                // 0 generates as fast as possible
+                tokens_per_second: (mock_tokens_per_second > 0.0).then_some(mock_tokens_per_second),
+                script,
+                context_size: mock_context_size,
+            })
+        }
+        _ => BackendKind::Llama,
+    };
+    if backend.uses_model_files() {
+        for model in &models {
+            model_file_close_check(&model.path);
+        }
+    }
+    let keyring = match (keys_file, &api_key) {
+        (None, None) => None,
+        (keys_file, api_key) => {
+            let keyring = match keys_file {
+                Some(path) => Keyring::load(path),
+                None => Ok(Keyring::new()),
+            };
+            match keyring.and_then(|mut keyring| {
+                if let Some(api_key) = api_key {
+                    keyring.add_unrestricted("default", api_key)?;
+                }
+                Ok(keyring)
+            }) {
+                Ok(keyring) => Some(keyring),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+    };
+    let tls = match (tls_cert, tls_key) {
+        (Some(cert), Some(key)) => match load_tls_acceptor(cert, key) {
+            Ok(acceptor) => Some(acceptor),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+    let settings = ModelSettings {
         num_threads,
         temp,
         freq_penalty,
         output_tokens,
-        api_key,
+        limits,
+        log_prompts,
+        backend,
+    };
+    let listener = TcpListener::bind(SocketAddr::new(host, port))?;
+    return run_webserver(
+        &models,
+        &templates,
+        settings,
+        listener,
+        tls,
+        keyring,
+        queue_depth,
+        Duration::from_secs(queue_timeout),
+        Duration::from_secs(job_ttl),
+        Duration::from_secs(session_ttl),
+        // 0 disables both the memory budget and the idle timeout
+        (memory_budget > 0).then_some(memory_budget * 1024 * 1024),
+        (model_idle_timeout > 0).then_some(Duration::from_secs(model_idle_timeout)),
+        // 0 disables the per-client limits as well
+        RateLimits {
+            requests_per_minute: (requests_per_minute > 0).then_some(requests_per_minute),
+            tokens_per_day: (tokens_per_day > 0).then_some(tokens_per_day),
+        },
     )
     .await;
 }
 
-// Intializes the LLM model interface, and starts the web server
+// Sets up the model registry, and starts the web server on the given listener
+#[allow(clippy::too_many_arguments)]
 async fn run_webserver(
-    model_path: &str,
-    port: u16,
-    num_threads: u16,
-    temp: f32,
-    freq_penalty: f32,
-    output_tokens: usize,
-    api_key: Option<&str>,
+    models: &[ModelSpec],
+    templates: &Templates,
+    settings: ModelSettings,
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    keyring: Option<Keyring>,
+    queue_depth: usize,
+    queue_timeout: Duration,
+    job_ttl: Duration,
+    session_ttl: Duration,
+    memory_budget: Option<u64>,
+    model_idle_timeout: Option<Duration>,
+    rate_limits: RateLimits,
 ) -> Result<(), Box<dyn Error>> {
-    // Setup LLMInterface using an Arc and Mutex to enable sharing the LLM interface across endpoints
-    let llm = Arc::new(Mutex::new(LLMInterface::new_local_llm(
-        model_path,
-        num_threads,
-        temp,
-        freq_penalty,
-        output_tokens,
-        api_key,
-    )?));
+    // Models are loaded by the first request which uses them,
+    // and requests take turns on each model through its own queue
+    let metrics = Arc::new(Metrics::new());
+    let mut registry = ModelRegistry::new(memory_budget, Arc::clone(&metrics));
+    for spec in models {
+        let queue = RequestQueue::new(queue_depth, queue_timeout);
+        let template = templates.get(spec.template.as_deref().unwrap_or(DEFAULT_TEMPLATE))?;
+        registry.add(Model::new(spec, template, settings.clone(), queue))?;
+    }
+
+    let state = Arc::new(ServerState {
+        models: registry,
+        jobs: JobStore::new(job_ttl),
+        sessions: SessionStore::new(session_ttl),
+        generations: Generations::new(),
+        keyring,
+        rate_limiter: RateLimiter::new(rate_limits),
+        metrics,
+        health: Health::new(),
+    });
+
+    // Periodically unload models which have not been used for a while
+    if let Some(idle_timeout) = model_idle_timeout {
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval((idle_timeout / 2).max(Duration::from_secs(1)));
+            loop {
+                interval.tick().await;
+                state.models.unload_idle(idle_timeout);
+            }
+        });
+    }
+
+    // Reload the loaded models from their files on SIGHUP
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangups = signal(SignalKind::hangup())?;
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                info!("Received SIGHUP, reloading models");
+                state.models.reload_all().await;
+            }
+        });
+    }
 
     // Setup the endpoints
-    let make_svc = make_service_fn(|_conn| {
-        let llm = Arc::clone(&llm);
+    let make_svc = make_service_fn(|conn: &AddrStream| {
+        let state = Arc::clone(&state);
+        let remote_addr = conn.remote_addr();
         async move {
-            Ok::<_, hyper::Error>(service_fn(move |req| route_requests(req, Arc::clone(&llm))))
+            Ok::<_, hyper::Error>(service_fn(move |req| {
+                logging_layer(req, Arc::clone(&state), remote_addr)
+            }))
         }
     });
 
     // Start the server
-    let addr = ([127, 0, 0, 1], port).into();
-    let server = Server::bind(&addr).serve(make_svc);
-    println!("\n\nOpen LLM Server");
-    println!("---------------");
-    println!("Server is running on http://{}\n", addr);
-    if let Err(e) = server.await {
-        eprintln!("Server error: {}", e);
+    let addr = listener.local_addr()?;
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    info!(
+        version = APP_VERSION,
+        backend = settings.backend.name(),
+        "Open LLM Server is running on {}://{}",
+        scheme,
+        addr
+    );
+    for model in state.models.iter() {
+        info!(model = %model.name, path = %model.path(), "Serving model");
+    }
+    let res = match tls {
+        Some(acceptor) => serve_tls(listener, acceptor, Arc::clone(&state)).await,
+        None => Server::from_tcp(listener)?
+            .serve(make_svc)
+            .await
+            .map_err(|e| e.into()),
+    };
+    if let Err(e) = res {
+        error!(error = %e, "Server error");
     }
 
     Ok(())
@@ -0,0 +1,286 @@
+use crate::auth::auth_layer;
+use crate::error::LLMError;
+use crate::llm_interface::GenerationStats;
+use crate::registry::Model;
+use crate::state::ServerState;
+use hyper::{header, Body, Request, Response};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Bucket bounds of the histograms timing things, in seconds
+const SECONDS_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0,
+];
+// Bucket bounds of the generation speed histogram, in tokens per second
+const RATE_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0];
+
+// A Prometheus histogram, with cumulative buckets
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum);
+        let _ = writeln!(out, "{}_count {}", name, self.count);
+    }
+}
+
+// Counters and histograms collected while the server runs.
+// Gauges (such as the queue depth) are read from the models when scraped instead.
+pub struct Metrics {
+    // Requests handled, by route and status code
+    requests: Mutex<BTreeMap<(&'static str, u16), u64>>,
+    queue_wait: Mutex<Histogram>,
+    model_load: Mutex<Histogram>,
+    time_to_first_token: Mutex<Histogram>,
+    tokens_per_second: Mutex<Histogram>,
+    prompt_tokens: AtomicU64,
+    cached_tokens: AtomicU64,
+    completion_tokens: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            requests: Mutex::new(BTreeMap::new()),
+            queue_wait: Mutex::new(Histogram::new(SECONDS_BUCKETS)),
+            model_load: Mutex::new(Histogram::new(SECONDS_BUCKETS)),
+            time_to_first_token: Mutex::new(Histogram::new(SECONDS_BUCKETS)),
+            tokens_per_second: Mutex::new(Histogram::new(RATE_BUCKETS)),
+            prompt_tokens: AtomicU64::new(0),
+            cached_tokens: AtomicU64::new(0),
+            completion_tokens: AtomicU64::new(0),
+        }
+    }
+
+    fn record_request(&self, route: &'static str, status: u16) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((route, status))
+            .or_insert(0) += 1;
+    }
+
+    // How long a request waited for its turn on a model
+    pub fn record_queue_wait(&self, waited: Duration) {
+        self.queue_wait
+            .lock()
+            .unwrap()
+            .observe(waited.as_secs_f64());
+    }
+
+    // How long it took to load a model
+    pub fn record_model_load(&self, took: Duration) {
+        self.model_load.lock().unwrap().observe(took.as_secs_f64());
+    }
+
+    // The tokens a generation evaluated and produced, and how quickly
+    pub fn record_generation(&self, stats: &GenerationStats) {
+        self.prompt_tokens
+            .fetch_add(stats.prompt_tokens as u64, Ordering::Relaxed);
+        self.cached_tokens
+            .fetch_add(stats.cached_tokens as u64, Ordering::Relaxed);
+        self.completion_tokens
+            .fetch_add(stats.completion_tokens as u64, Ordering::Relaxed);
+        if let (Some(started), Some(first_token)) = (stats.started, stats.first_token) {
+            self.time_to_first_token
+                .lock()
+                .unwrap()
+                .observe(first_token.duration_since(started).as_secs_f64());
+        }
+        // The speed of generating tokens, leaving out evaluating the prompt
+        if let (Some(first_token), Some(last_token)) = (stats.first_token, stats.last_token) {
+            let took = last_token.duration_since(first_token).as_secs_f64();
+            if stats.completion_tokens > 1 && took > 0.0 {
+                self.tokens_per_second
+                    .lock()
+                    .unwrap()
+                    .observe((stats.completion_tokens - 1) as f64 / took);
+            }
+        }
+    }
+
+    // Renders the metrics in the Prometheus text format
+    fn render(&self, state: &ServerState) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP open_llm_requests_total Requests handled, by route and status code\n");
+        out.push_str("# TYPE open_llm_requests_total counter\n");
+        for ((route, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "open_llm_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                route, status, count
+            );
+        }
+
+        let mut gauge = |name: &str, help: &str, value: &dyn Fn(&Model) -> u64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            for model in state.models.iter() {
+                let _ = writeln!(
+                    out,
+                    "{}{{model=\"{}\"}} {}",
+                    name,
+                    escape_label(&model.name),
+                    value(model)
+                );
+            }
+        };
+        gauge(
+            "open_llm_queue_depth",
+            "Requests waiting for their turn on a model",
+            &|model| model.queue.queued() as u64,
+        );
+        gauge(
+            "open_llm_model_busy",
+            "Whether a request holds a model",
+            &|model| model.queue.is_busy() as u64,
+        );
+        gauge(
+            "open_llm_model_loaded",
+            "Whether a model is loaded",
+            &|model| model.queue.is_loaded() as u64,
+        );
+
+        let counter = |out: &mut String, name: &str, help: &str, value: &AtomicU64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        };
+        counter(
+            &mut out,
+            "open_llm_prompt_tokens_total",
+            "Prompt tokens submitted, including those reused from the prompt cache",
+            &self.prompt_tokens,
+        );
+        counter(
+            &mut out,
+            "open_llm_prompt_tokens_cached_total",
+            "Prompt tokens whose evaluated state was reused from an earlier prompt",
+            &self.cached_tokens,
+        );
+        counter(
+            &mut out,
+            "open_llm_completion_tokens_total",
+            "Tokens generated",
+            &self.completion_tokens,
+        );
+
+        self.queue_wait.lock().unwrap().render(
+            &mut out,
+            "open_llm_queue_wait_seconds",
+            "Time requests waited for their turn on a model",
+        );
+        self.model_load.lock().unwrap().render(
+            &mut out,
+            "open_llm_model_load_seconds",
+            "Time taken to load a model",
+        );
+        self.time_to_first_token.lock().unwrap().render(
+            &mut out,
+            "open_llm_time_to_first_token_seconds",
+            "Time from submitting a prompt to its first generated token",
+        );
+        self.tokens_per_second.lock().unwrap().render(
+            &mut out,
+            "open_llm_tokens_per_second",
+            "Speed of generating tokens, after evaluating the prompt",
+        );
+        out
+    }
+}
+
+// Counts every request by route and status code
+pub async fn metrics_layer(
+    req: Request<Body>,
+    state: Arc<ServerState>,
+    remote_addr: SocketAddr,
+) -> Result<Response<Body>, LLMError> {
+    let route = route_label(req.uri().path());
+    let res = auth_layer(req, Arc::clone(&state), remote_addr).await;
+    let status = match &res {
+        Ok(res) => res.status().as_u16(),
+        Err(_) => 500,
+    };
+    state.metrics.record_request(route, status);
+    res
+}
+
+// Exposes the metrics for Prometheus to scrape
+pub async fn metrics_endpoint(state: &ServerState) -> Result<Response<Body>, LLMError> {
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(state.metrics.render(state)))?)
+}
+
+// The route a path is counted under. Ids in paths are left out,
+// and unknown paths are counted together, so the number of series stays small.
+fn route_label(path: &str) -> &'static str {
+    match path {
+        "/" => "/",
+        "/submit_prompt" => "/submit_prompt",
+        "/generate_embeddings" => "/generate_embeddings",
+        "/submit_prompt_streaming" => "/submit_prompt_streaming",
+        "/chat" => "/chat",
+        "/sessions" => "/sessions",
+        path if path.starts_with("/sessions/") && path.ends_with("/messages") => {
+            "/sessions/{id}/messages"
+        }
+        path if path.starts_with("/sessions/") => "/sessions/{id}",
+        "/v1/completions" => "/v1/completions",
+        "/v1/chat/completions" => "/v1/chat/completions",
+        "/v1/models" => "/v1/models",
+        "/models" => "/models",
+        "/jobs" => "/jobs",
+        path if path.starts_with("/jobs/") => "/jobs/{id}",
+        path if path.starts_with("/cancel/") => "/cancel/{id}",
+        "/admin/reload" => "/admin/reload",
+        "/is_busy" => "/is_busy",
+        "/metrics" => "/metrics",
+        "/health/live" => "/health/live",
+        "/health/ready" => "/health/ready",
+        _ => "other",
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,251 @@
+use crate::backend::Backend;
+use crate::cancel;
+use crate::error::LLMError;
+use crate::fs_reading::load_toml_or_yaml;
+use crate::llm_interface::{record_cached_tokens, record_prompt, record_token, TokenSender};
+use crate::registry::ModelSettings;
+use crate::sampling::Sampling;
+use async_trait::async_trait;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use tracing::{debug, info};
+
+// Struct to represent the script file given with `--mock_script`
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ScriptFile {
+    responses: Vec<ScriptedResponse>,
+}
+
+// Struct to represent a single response of the script file
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptedResponse {
+    // Prompts containing this text get the response
+    prompt: String,
+    response: String,
+}
+
+// The context window of mock models, unless set otherwise
+pub const DEFAULT_CONTEXT_SIZE: usize = 2048;
+
+// How the mock backend responds
+#[derive(Debug, Clone)]
+pub struct MockSettings {
+    // How long evaluating a prompt takes, before the first token is generated
+    pub latency: Duration,
+    // How many tokens are generated per second, as fast as possible if not set
+    pub tokens_per_second: Option<f32>,
+    // Responses to give to matching prompts, the first match wins.
+    // Prompts without a match are echoed back.
+    pub script: Vec<ScriptedResponse>,
+    // How many tokens fit in the context window
+    pub context_size: usize,
+}
+
+impl Default for MockSettings {
+    fn default() -> Self {
+        Self {
+            latency: Duration::ZERO,
+            tokens_per_second: None,
+            script: Vec::new(),
+            context_size: DEFAULT_CONTEXT_SIZE,
+        }
+    }
+}
+
+// Loads scripted responses from the mock script file
+pub fn load_script(path: &str) -> Result<Vec<ScriptedResponse>, String> {
+    let file: ScriptFile = load_toml_or_yaml(path, "mock script")?;
+    Ok(file.responses)
+}
+
+// The number of dimensions of the mock embedding vectors
+const EMBEDDING_DIMENSION: usize = 32;
+
+// A deterministic stand-in for a model, so the HTTP API can be exercised without model files.
+// Tokens are whitespace separated words, and the same prompt always gets the same response.
+pub struct MockBackend {
+    name: String,
+    settings: MockSettings,
+    log_prompts: bool,
+    // The last prompt and response, standing in for llama.cpp's cached state
+    cached: String,
+    // Standing in for the model being loaded a second time in embedding mode
+    embeddings_loaded: bool,
+}
+
+impl MockBackend {
+    pub fn new(name: &str, settings: &ModelSettings, mock: MockSettings) -> Self {
+        Self {
+            name: name.to_string(),
+            settings: mock,
+            log_prompts: settings.log_prompts,
+            cached: String::new(),
+            embeddings_loaded: false,
+        }
+    }
+
+    // The scripted response to the prompt, or else the prompt itself
+    fn response_to(&self, prompt_text: &str) -> String {
+        self.settings
+            .script
+            .iter()
+            .find(|scripted| prompt_text.contains(&scripted.prompt))
+            .map(|scripted| scripted.response.clone())
+            .unwrap_or_else(|| prompt_text.to_string())
+    }
+
+    // Generate the response token by token, at the configured pace.
+    // Runs on a blocking thread like any other generation, so it simply sleeps.
+    fn generate(
+        &mut self,
+        prompt_text: &str,
+        sampling: &Sampling,
+        token_tx: Option<TokenSender>,
+    ) -> Result<String, LLMError> {
+        if self.log_prompts {
+            debug!(model = %self.name, prompt = prompt_text, "Prompt received");
+        } else {
+            debug!(model = %self.name, "Prompt received");
+        }
+        if cancel::current_is_cancelled() {
+            return Err(LLMError::Cancelled);
+        }
+        let prompt_tokens = split_tokens(prompt_text).len();
+        if prompt_tokens >= self.settings.context_size {
+            return Err(LLMError::InvalidParameters(
+                "the prompt is too long for the model's context window".to_string(),
+            ));
+        }
+        record_prompt(prompt_tokens);
+        // Count the tokens the prompt shares with the last one, as a real prompt cache would reuse
+        let cached = split_tokens(prompt_text)
+            .iter()
+            .zip(split_tokens(&self.cached))
+            .take_while(|(token, cached)| *token == cached)
+            .count();
+        record_cached_tokens(cached);
+        self.cached = prompt_text.to_string();
+
+        let mut text = self.response_to(prompt_text);
+        sampling.apply_stop_sequences(&mut text);
+        let mut tokens = split_tokens(&text);
+        if sampling.max_tokens() > 0 {
+            tokens.truncate(sampling.max_tokens());
+        }
+
+        std::thread::sleep(self.settings.latency);
+        let mut response = String::new();
+        for token in tokens {
+            if let Some(rate) = self.settings.tokens_per_second {
+                std::thread::sleep(Duration::from_secs_f32(1.0 / rate));
+            }
+            record_token();
+            let delivered = match &token_tx {
+                Some(tx) => tx.unbounded_send(token.to_string()).is_ok(),
+                None => true,
+            };
+            // Stop like a real generation would if cancelled or the client went away
+            if !delivered || cancel::current_is_cancelled() {
+                info!(model = %self.name, "Prompt cancelled");
+                return Err(LLMError::Cancelled);
+            }
+            response.push_str(token);
+            self.cached.push_str(token);
+        }
+        Ok(response)
+    }
+}
+
+#[async_trait(?Send)]
+impl Backend for MockBackend {
+    fn model_name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn count_tokens(&self, text: &str) -> Result<usize, LLMError> {
+        Ok(split_tokens(text).len())
+    }
+
+    fn context_size(&self) -> usize {
+        self.settings.context_size
+    }
+
+    async fn submit_prompt(
+        &mut self,
+        prompt_text: &str,
+        sampling: &Sampling,
+    ) -> Result<String, LLMError> {
+        self.generate(prompt_text, sampling, None)
+    }
+
+    async fn submit_prompt_streaming(
+        &mut self,
+        prompt_text: &str,
+        sampling: &Sampling,
+        token_tx: TokenSender,
+    ) -> Result<String, LLMError> {
+        self.generate(prompt_text, sampling, Some(token_tx))
+    }
+
+    // Generating always works, so there is nothing to check
+    fn check_generation(&mut self) -> Result<(), LLMError> {
+        Ok(())
+    }
+
+    fn embeddings_loaded(&self) -> bool {
+        self.embeddings_loaded
+    }
+
+    fn load_embeddings(&mut self) -> Result<(), LLMError> {
+        self.embeddings_loaded = true;
+        Ok(())
+    }
+
+    // A unit vector derived from the hash of the input
+    async fn generate_embeddings(&mut self, input_text: &str) -> Result<Vec<f32>, LLMError> {
+        if cancel::current_is_cancelled() {
+            return Err(LLMError::Cancelled);
+        }
+        if !self.embeddings_loaded {
+            return Err(LLMError::InitializingLLMFailed);
+        }
+        if split_tokens(input_text).len() >= self.settings.context_size {
+            return Err(LLMError::InvalidParameters(
+                "the input is too long for the model's context window".to_string(),
+            ));
+        }
+        let digest = Sha256::digest(input_text.as_bytes());
+        let embedding: Vec<f32> = digest
+            .iter()
+            .cycle()
+            .take(EMBEDDING_DIMENSION)
+            .map(|byte| *byte as f32 / 127.5 - 1.0)
+            .collect();
+        let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+        Ok(embedding
+            .iter()
+            .map(|v| v / norm.max(f32::EPSILON))
+            .collect())
+    }
+}
+
+// Splits text into tokens, each a word along with the whitespace before it
+fn split_tokens(text: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut in_word = false;
+    for (i, c) in text.char_indices() {
+        if c.is_whitespace() && in_word {
+            tokens.push(&text[start..i]);
+            start = i;
+        }
+        in_word = !c.is_whitespace();
+    }
+    if start < text.len() {
+        tokens.push(&text[start..]);
+    }
+    tokens
+}
@@ -0,0 +1,514 @@
+use crate::backend::Backend;
+use crate::endpoints::sse_event;
+use crate::error::LLMError;
+use crate::queue::QueueGuard;
+use crate::registry::Model;
+use crate::responses::json_response;
+use crate::sampling::{Sampling, SamplingParams};
+use crate::state::ServerState;
+use crate::templates::{self, ChatMessage};
+use futures::channel::mpsc;
+use futures::{future, stream, StreamExt};
+use hyper::{header, StatusCode};
+use hyper::{Body, Response};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+use tracing::warn;
+
+// Counter used to give every completion a unique id
+static COMPLETION_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Struct to represent an OpenAI `/v1/completions` request
+#[derive(Deserialize, Debug)]
+struct CompletionRequest {
+    prompt: CompletionPrompt,
+    #[serde(default)]
+    stream: bool,
+    #[serde(flatten)]
+    sampling: OpenAISampling,
+}
+
+// The prompt of a completion request, either a string or a single-item list of strings
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum CompletionPrompt {
+    Single(String),
+    Batch(Vec<String>),
+}
+
+// Struct to represent an OpenAI `/v1/chat/completions` request
+#[derive(Deserialize, Debug)]
+struct ChatCompletionRequest {
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    stream: bool,
+    #[serde(flatten)]
+    sampling: OpenAISampling,
+}
+
+// Sampling parameters of both completion requests, using the OpenAI names.
+// `top_k` and `repeat_penalty` are not part of the OpenAI API, but are accepted as extensions.
+#[derive(Deserialize, Debug, Default)]
+struct OpenAISampling {
+    max_tokens: Option<usize>,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    top_k: Option<i32>,
+    repeat_penalty: Option<f32>,
+    seed: Option<i32>,
+    stop: Option<StopSequences>,
+}
+
+// OpenAI accepts either a single stop sequence or a list of them
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum StopSequences {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl From<OpenAISampling> for SamplingParams {
+    fn from(sampling: OpenAISampling) -> Self {
+        Self {
+            temp: sampling.temperature,
+            top_p: sampling.top_p,
+            top_k: sampling.top_k,
+            repeat_penalty: sampling.repeat_penalty,
+            n_tok_predict: sampling.max_tokens,
+            seed: sampling.seed,
+            stop: sampling.stop.map(|stop| match stop {
+                StopSequences::Single(stop) => vec![stop],
+                StopSequences::Multiple(stops) => stops,
+            }),
+        }
+    }
+}
+
+// Struct to represent an OpenAI completion (or streamed completion chunk) response
+#[derive(Serialize)]
+struct CompletionResponse {
+    id: String,
+    object: &'static str,
+    created: u64,
+    model: String,
+    choices: Vec<CompletionChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<Usage>,
+}
+
+// Struct to represent a single choice of a completion response
+#[derive(Serialize)]
+struct CompletionChoice {
+    text: String,
+    index: usize,
+    logprobs: Option<()>,
+    finish_reason: Option<&'static str>,
+}
+
+// Struct to represent an OpenAI chat completion (or streamed chat completion chunk) response
+#[derive(Serialize)]
+struct ChatCompletionResponse {
+    id: String,
+    object: &'static str,
+    created: u64,
+    model: String,
+    choices: Vec<ChatChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<Usage>,
+}
+
+// Struct to represent a single choice of a chat completion response.
+// Full responses carry a `message`, streamed chunks carry a `delta`.
+#[derive(Serialize)]
+struct ChatChoice {
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<ChatMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delta: Option<ChatDelta>,
+    finish_reason: Option<&'static str>,
+}
+
+// Struct to represent the incremental content of a streamed chat completion chunk
+#[derive(Serialize)]
+struct ChatDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+}
+
+// Struct to represent the token usage of a completion
+#[derive(Serialize, Clone, Copy)]
+struct Usage {
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    total_tokens: usize,
+}
+
+// Struct to represent an OpenAI error response
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: ErrorBody,
+}
+
+// Struct to represent the details of an OpenAI error response
+#[derive(Serialize)]
+struct ErrorBody {
+    message: String,
+    #[serde(rename = "type")]
+    error_type: &'static str,
+    param: Option<String>,
+    code: Option<String>,
+}
+
+// Struct to represent the `/v1/models` response
+#[derive(Serialize)]
+struct ModelList {
+    object: &'static str,
+    data: Vec<ModelInfo>,
+}
+
+// Struct to represent a single model of the `/v1/models` response
+#[derive(Serialize)]
+struct ModelInfo {
+    id: String,
+    object: &'static str,
+    created: u64,
+    owned_by: &'static str,
+}
+
+// Which of the OpenAI endpoints a generation is being served for
+#[derive(Clone, Copy)]
+enum CompletionKind {
+    Text,
+    Chat,
+}
+
+// Identifies a single completion and builds its response objects
+struct Completion {
+    kind: CompletionKind,
+    id: String,
+    created: u64,
+    model: String,
+}
+
+impl Completion {
+    fn new(kind: CompletionKind, model: String) -> Self {
+        let prefix = match kind {
+            CompletionKind::Text => "cmpl",
+            CompletionKind::Chat => "chatcmpl",
+        };
+        let created = unix_timestamp();
+        let count = COMPLETION_COUNTER.fetch_add(1, Ordering::Relaxed);
+        Self {
+            kind,
+            id: format!("{}-{}{}", prefix, created, count),
+            created,
+            model,
+        }
+    }
+
+    // Builds the full (non-streamed) response
+    fn response(
+        &self,
+        text: String,
+        finish_reason: &'static str,
+        usage: Usage,
+    ) -> Result<Response<Body>, LLMError> {
+        match self.kind {
+            CompletionKind::Text => json_response(
+                StatusCode::OK,
+                &CompletionResponse {
+                    id: self.id.clone(),
+                    object: "text_completion",
+                    created: self.created,
+                    model: self.model.clone(),
+                    choices: vec![CompletionChoice {
+                        text,
+                        index: 0,
+                        logprobs: None,
+                        finish_reason: Some(finish_reason),
+                    }],
+                    usage: Some(usage),
+                },
+            ),
+            CompletionKind::Chat => json_response(
+                StatusCode::OK,
+                &ChatCompletionResponse {
+                    id: self.id.clone(),
+                    object: "chat.completion",
+                    created: self.created,
+                    model: self.model.clone(),
+                    choices: vec![ChatChoice {
+                        index: 0,
+                        message: Some(ChatMessage {
+                            role: "assistant".to_string(),
+                            content: text,
+                        }),
+                        delta: None,
+                        finish_reason: Some(finish_reason),
+                    }],
+                    usage: Some(usage),
+                },
+            ),
+        }
+    }
+
+    // Formats a streamed chunk as a Server-Sent Event
+    fn chunk(
+        &self,
+        role: Option<&'static str>,
+        text: Option<String>,
+        finish_reason: Option<&'static str>,
+    ) -> String {
+        match self.kind {
+            CompletionKind::Text => sse_event(
+                None,
+                &CompletionResponse {
+                    id: self.id.clone(),
+                    object: "text_completion",
+                    created: self.created,
+                    model: self.model.clone(),
+                    choices: vec![CompletionChoice {
+                        text: text.unwrap_or_default(),
+                        index: 0,
+                        logprobs: None,
+                        finish_reason,
+                    }],
+                    usage: None,
+                },
+            ),
+            CompletionKind::Chat => sse_event(
+                None,
+                &ChatCompletionResponse {
+                    id: self.id.clone(),
+                    object: "chat.completion.chunk",
+                    created: self.created,
+                    model: self.model.clone(),
+                    choices: vec![ChatChoice {
+                        index: 0,
+                        message: None,
+                        delta: Some(ChatDelta {
+                            role,
+                            content: text,
+                        }),
+                        finish_reason,
+                    }],
+                    usage: None,
+                },
+            ),
+        }
+    }
+}
+
+// A completion request, validated against the model it is for before it is queued
+pub struct CompletionJob {
+    kind: CompletionKind,
+    prompt: String,
+    sampling: Sampling,
+    stream: bool,
+}
+
+// Parse and validate an OpenAI completions request
+pub fn parse_completions(body: &[u8], model: &Model) -> Result<CompletionJob, String> {
+    let input: CompletionRequest = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    let prompt = match input.prompt {
+        CompletionPrompt::Single(prompt) => prompt,
+        CompletionPrompt::Batch(mut prompts) if prompts.len() == 1 => prompts.remove(0),
+        CompletionPrompt::Batch(_) => return Err("Exactly one prompt must be provided".to_string()),
+    };
+    let sampling = model
+        .sampling(&input.sampling.into())
+        .map_err(|e| e.to_string())?;
+    Ok(CompletionJob {
+        kind: CompletionKind::Text,
+        prompt,
+        sampling,
+        stream: input.stream,
+    })
+}
+
+// Parse and validate an OpenAI chat completions request,
+// rendering the messages in the format the model was trained on
+pub fn parse_chat_completions(body: &[u8], model: &Model) -> Result<CompletionJob, String> {
+    let input: ChatCompletionRequest = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    templates::check_messages(&input.messages)?;
+    let mut sampling = model
+        .sampling(&input.sampling.into())
+        .map_err(|e| e.to_string())?;
+    // Stop where the template's turns end, on top of the request's own stop sequences
+    sampling.add_stop_sequences(&model.template.stop);
+    Ok(CompletionJob {
+        kind: CompletionKind::Chat,
+        prompt: model.template.render(&input.messages),
+        sampling,
+        stream: input.stream,
+    })
+}
+
+// Lists the models being served
+pub async fn models_endpoint(state: &ServerState) -> Result<Response<Body>, LLMError> {
+    let created = unix_timestamp();
+    json_response(
+        StatusCode::OK,
+        &ModelList {
+            object: "list",
+            data: state
+                .models
+                .iter()
+                .map(|model| ModelInfo {
+                    id: model.name.clone(),
+                    object: "model",
+                    created,
+                    owned_by: "open-llm-server",
+                })
+                .collect(),
+        },
+    )
+}
+
+// Builds an OpenAI style error response with the given status
+pub fn error_response(status: StatusCode, message: &str) -> Result<Response<Body>, LLMError> {
+    let error_type = if status.is_client_error() {
+        "invalid_request_error"
+    } else {
+        "server_error"
+    };
+    json_response(
+        status,
+        &ErrorResponse {
+            error: ErrorBody {
+                message: message.to_string(),
+                error_type,
+                param: None,
+                code: None,
+            },
+        },
+    )
+}
+
+// Handle an OpenAI (chat) completions request, running the prompt on the LLM
+// and sending either the full response or an event stream through the channel
+pub async fn completions_endpoint(
+    job: CompletionJob,
+    mut llm_guard: QueueGuard,
+    tx: oneshot::Sender<Result<Response<Body>, LLMError>>,
+) {
+    let CompletionJob {
+        kind,
+        prompt,
+        sampling,
+        stream,
+    } = job;
+    let completion = Completion::new(kind, llm_guard.model_name());
+    let prompt_tokens = llm_guard.count_tokens(&prompt).unwrap_or(0);
+
+    if !stream {
+        let res = match llm_guard.submit_prompt(&prompt, &sampling).await {
+            Ok(text) => {
+                let (finish_reason, usage) = finish(&*llm_guard, &sampling, &text, prompt_tokens);
+                completion.response(text, finish_reason, usage)
+            }
+            // A prompt which doesn't fit in the context window is the client's mistake
+            Err(error @ LLMError::InvalidParameters(_)) => {
+                error_response(StatusCode::BAD_REQUEST, &error.to_string())
+            }
+            Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &error.to_string()),
+        };
+        return send_response(tx, res);
+    }
+
+    // Stream every token as a chunk, followed by a final chunk with the finish reason
+    let completion = Arc::new(completion);
+    let (token_tx, token_rx) = mpsc::unbounded::<String>();
+    let (done_tx, done_rx) = futures::channel::oneshot::channel::<String>();
+    let first = match kind {
+        CompletionKind::Chat => Some(completion.chunk(Some("assistant"), None, None)),
+        CompletionKind::Text => None,
+    };
+    let chunk_completion = Arc::clone(&completion);
+    let events = stream::iter(first)
+        .chain(token_rx.map(move |token| chunk_completion.chunk(None, Some(token), None)))
+        .chain(stream::once(done_rx).filter_map(|done| future::ready(done.ok())))
+        .map(Ok::<_, LLMError>);
+    let res = Response::builder()
+        .header(header::CONTENT_TYPE, "text/event-stream")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::wrap_stream(events))
+        .map_err(LLMError::from);
+    if tx.send(res).is_err() {
+        warn!("Failed to send completion response");
+        return;
+    }
+
+    let done = match llm_guard
+        .submit_prompt_streaming(&prompt, &sampling, token_tx)
+        .await
+    {
+        Ok(text) => {
+            let (finish_reason, _) = finish(&*llm_guard, &sampling, &text, prompt_tokens);
+            completion.chunk(None, None, Some(finish_reason))
+        }
+        Err(error) => sse_event(
+            None,
+            &ErrorResponse {
+                error: ErrorBody {
+                    message: error.to_string(),
+                    error_type: match error {
+                        LLMError::InvalidParameters(_) => "invalid_request_error",
+                        _ => "server_error",
+                    },
+                    param: None,
+                    code: None,
+                },
+            },
+        ),
+    };
+    if done_tx.send(done + "data: [DONE]\n\n").is_err() {
+        warn!("Failed to send completion response");
+    }
+}
+
+// Computes the finish reason and token usage of a finished generation
+fn finish(
+    llm: &dyn Backend,
+    sampling: &Sampling,
+    text: &str,
+    prompt_tokens: usize,
+) -> (&'static str, Usage) {
+    let completion_tokens = llm.count_tokens(text).unwrap_or(0);
+    let max_tokens = sampling.max_tokens();
+    let finish_reason = if max_tokens > 0 && completion_tokens >= max_tokens {
+        "length"
+    } else {
+        "stop"
+    };
+    let usage = Usage {
+        prompt_tokens,
+        completion_tokens,
+        total_tokens: prompt_tokens + completion_tokens,
+    };
+    (finish_reason, usage)
+}
+
+// Sends a response through the channel
+fn send_response(
+    tx: oneshot::Sender<Result<Response<Body>, LLMError>>,
+    res: Result<Response<Body>, LLMError>,
+) {
+    if tx.send(res).is_err() {
+        warn!("Failed to send completion response");
+    }
+}
+
+// Seconds since the unix epoch
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,312 @@
+use crate::cancel;
+use crate::error::LLMError;
+use crate::sampling::Sampling;
+use llm_chain_llama::PerInvocation;
+use llm_chain_llama_sys::{
+    llama_context, llama_context_default_params, llama_eval, llama_free, llama_get_logits,
+    llama_init_from_file, llama_n_ctx, llama_n_vocab,
+    llama_sample_frequency_and_presence_penalties, llama_sample_repetition_penalty,
+    llama_sample_tail_free, llama_sample_temperature, llama_sample_token,
+    llama_sample_token_greedy, llama_sample_top_k, llama_sample_top_p, llama_sample_typical,
+    llama_set_rng_seed, llama_token, llama_token_data, llama_token_data_array, llama_token_eos,
+    llama_token_to_str, llama_tokenize,
+};
+use std::ffi::{CStr, CString};
+
+// How many prompt tokens are evaluated at once, as llama.cpp's examples do
+const BATCH_SIZE: usize = 512;
+
+// Where generations stop when the request gives no stop sequences, as llm-chain-llama does
+const DEFAULT_STOP_SEQUENCE: &str = "\n\n";
+
+// A llama.cpp context which only evaluates the part of a prompt its last one didn't share
+pub struct PromptCache {
+    ctx: *mut llama_context,
+    num_threads: u16,
+    // The tokens held by the context's KV cache, in order
+    tokens: Vec<llama_token>,
+}
+
+impl PromptCache {
+    // Load the model at the given path
+    pub fn new(model_path: &str, num_threads: u16) -> Result<Self, LLMError> {
+        let c_path = CString::new(model_path)
+            .map_err(|_| LLMError::Custom("Invalid model path".to_string()))?;
+        let params = unsafe { llama_context_default_params() };
+        let ctx = unsafe { llama_init_from_file(c_path.as_ptr(), params) };
+        if ctx.is_null() {
+            return Err(LLMError::InitializingLLMFailed);
+        }
+        Ok(Self {
+            ctx,
+            num_threads,
+            tokens: Vec::new(),
+        })
+    }
+
+    // The number of tokens the context holds
+    pub fn context_size(&self) -> usize {
+        unsafe { llama_n_ctx(self.ctx).max(0) as usize }
+    }
+
+    // Tokenize the text, starting with the beginning-of-sentence token
+    pub fn tokenize(&self, text: &str) -> Result<Vec<llama_token>, LLMError> {
+        let c_text = CString::new(text)
+            .map_err(|_| LLMError::Custom("Text contains a null byte".to_string()))?;
+        // There can never be more tokens than bytes, plus the BOS token
+        let mut tokens: Vec<llama_token> = Vec::with_capacity(text.len() + 1);
+        let n_tokens = unsafe {
+            llama_tokenize(
+                self.ctx,
+                c_text.as_ptr(),
+                tokens.as_mut_ptr(),
+                tokens.capacity() as i32,
+                true,
+            )
+        };
+        if n_tokens < 0 {
+            return Err(LLMError::Custom("Failed to tokenize text".to_string()));
+        }
+        unsafe { tokens.set_len(n_tokens as usize) };
+        Ok(tokens)
+    }
+
+    pub fn set_seed(&mut self, seed: i32) {
+        unsafe { llama_set_rng_seed(self.ctx, seed) };
+    }
+
+    // Evaluate the prompt, reusing the state of the longest prefix it shares with the cached tokens.
+    // Returns how many of the prompt's tokens were reused, not counting the beginning-of-sentence one.
+    pub fn evaluate(&mut self, prompt_text: &str) -> Result<usize, LLMError> {
+        let prompt = self.tokenize(prompt_text)?;
+        if prompt.len() >= self.context_size() {
+            return Err(LLMError::InvalidParameters(
+                "the prompt is too long for the model's context window".to_string(),
+            ));
+        }
+        let (kept, reused) = reusable_prefix(&self.tokens, &prompt);
+        self.tokens.truncate(kept);
+        for batch in prompt[kept..].chunks(BATCH_SIZE) {
+            // Long prompts can take a while, so a cancelled request stops between batches
+            if cancel::current_is_cancelled() {
+                return Err(LLMError::Cancelled);
+            }
+            self.eval(batch)?;
+        }
+        Ok(reused)
+    }
+
+    // Generate a response to the evaluated prompt, passing each token to `on_token`.
+    // Stops at the end of the text, a stop sequence, the request's token limit or a full context.
+    pub fn generate(
+        &mut self,
+        sampling: &Sampling,
+        mut on_token: impl FnMut(&str) -> Result<(), LLMError>,
+    ) -> Result<String, LLMError> {
+        let native_stop = sampling
+            .options
+            .stop_sequence
+            .as_deref()
+            .unwrap_or(DEFAULT_STOP_SEQUENCE);
+        let max_tokens = sampling.max_tokens();
+        let mut response = String::new();
+        let mut generated = 0;
+        while (max_tokens == 0 || generated < max_tokens) && self.tokens.len() < self.context_size()
+        {
+            let token = self.sample(&sampling.options);
+            if token == unsafe { llama_token_eos() } {
+                break;
+            }
+            generated += 1;
+            let piece = self.token_to_str(token);
+            on_token(&piece)?;
+            response.push_str(&piece);
+            if response.contains(native_stop)
+                || sampling
+                    .stop
+                    .iter()
+                    .any(|stop| response.contains(stop.as_str()))
+            {
+                break;
+            }
+            // Evaluated tokens stay cached, so the next prompt can continue from the response
+            self.eval(&[token])?;
+        }
+        if let Some(cutoff) = response.find(native_stop) {
+            response.truncate(cutoff);
+        }
+        Ok(response)
+    }
+
+    // Check that the model still generates: evaluate the text after the cached tokens and sample a
+    // token, without adding either to the cache. The next prompt overwrites those positions of the
+    // KV cache as it is evaluated, so the cached prefix is still reused.
+    pub fn probe(&mut self, text: &str) -> Result<(), LLMError> {
+        let tokens = self.tokenize(text)?;
+        if self.tokens.len() + tokens.len() >= self.context_size() {
+            // There is no room after the cached tokens, so they are given up instead
+            self.tokens.clear();
+        }
+        let res = unsafe {
+            llama_eval(
+                self.ctx,
+                tokens.as_ptr(),
+                tokens.len() as i32,
+                self.tokens.len() as i32,
+                self.num_threads as i32,
+            )
+        };
+        if res != 0 {
+            self.tokens.clear();
+            return Err(LLMError::SubmittingPromptFailed);
+        }
+        self.sample(&PerInvocation::new());
+        Ok(())
+    }
+
+    fn eval(&mut self, tokens: &[llama_token]) -> Result<(), LLMError> {
+        let res = unsafe {
+            llama_eval(
+                self.ctx,
+                tokens.as_ptr(),
+                tokens.len() as i32,
+                self.tokens.len() as i32,
+                self.num_threads as i32,
+            )
+        };
+        if res != 0 {
+            // The KV cache may have been partly overwritten, so none of it can be trusted
+            self.tokens.clear();
+            return Err(LLMError::SubmittingPromptFailed);
+        }
+        self.tokens.extend_from_slice(tokens);
+        Ok(())
+    }
+
+    // Sample the next token from the logits of the last evaluated one,
+    // with the same defaults as llm-chain-llama for any option which is not set
+    fn sample(&self, options: &PerInvocation) -> llama_token {
+        let n_vocab = unsafe { llama_n_vocab(self.ctx) } as usize;
+        let logits = unsafe { std::slice::from_raw_parts(llama_get_logits(self.ctx), n_vocab) };
+        let mut candidates: Vec<llama_token_data> = logits
+            .iter()
+            .enumerate()
+            .map(|(id, &logit)| llama_token_data {
+                id: id as llama_token,
+                logit,
+                p: 0.0,
+            })
+            .collect();
+        let mut candidates = llama_token_data_array {
+            data: candidates.as_mut_ptr(),
+            size: candidates.len(),
+            sorted: false,
+        };
+
+        // Penalize repeating the most recent tokens
+        let repeat_last_n = match options.repeat_last_n.unwrap_or(64) {
+            n if n < 0 => self.tokens.len(),
+            n => (n as usize).min(self.tokens.len()),
+        };
+        let last_tokens = &self.tokens[self.tokens.len() - repeat_last_n..];
+        unsafe {
+            llama_sample_repetition_penalty(
+                self.ctx,
+                &mut candidates,
+                last_tokens.as_ptr(),
+                last_tokens.len(),
+                options.repeat_penalty.unwrap_or(1.1),
+            );
+            llama_sample_frequency_and_presence_penalties(
+                self.ctx,
+                &mut candidates,
+                last_tokens.as_ptr(),
+                last_tokens.len(),
+                options.frequency_penalty.unwrap_or(0.0),
+                options.presence_penalty.unwrap_or(0.0),
+            );
+        }
+
+        let temp = options.temp.unwrap_or(0.8);
+        if temp <= 0.0 {
+            return unsafe { llama_sample_token_greedy(self.ctx, &mut candidates) };
+        }
+        let top_k = match options.top_k.unwrap_or(40) {
+            k if k <= 0 => n_vocab as i32,
+            k => k,
+        };
+        unsafe {
+            llama_sample_top_k(self.ctx, &mut candidates, top_k, 1);
+            llama_sample_tail_free(self.ctx, &mut candidates, options.tfs_z.unwrap_or(1.0), 1);
+            llama_sample_typical(
+                self.ctx,
+                &mut candidates,
+                options.typical_p.unwrap_or(1.0),
+                1,
+            );
+            llama_sample_top_p(self.ctx, &mut candidates, options.top_p.unwrap_or(0.95), 1);
+            llama_sample_temperature(self.ctx, &mut candidates, temp);
+            llama_sample_token(self.ctx, &mut candidates)
+        }
+    }
+
+    fn token_to_str(&self, token: llama_token) -> String {
+        let c_str = unsafe { CStr::from_ptr(llama_token_to_str(self.ctx, token)) };
+        c_str.to_string_lossy().into_owned()
+    }
+}
+
+// How much of the cached tokens the prompt can keep: the length of the prefix they share, and how
+// many of the prompt's tokens that reuses, not counting the beginning-of-sentence one.
+// The first token is sampled from the logits of the prompt's last token,
+// so that one is evaluated again even if the whole prompt is cached.
+fn reusable_prefix(cached: &[llama_token], prompt: &[llama_token]) -> (usize, usize) {
+    let kept = cached
+        .iter()
+        .zip(prompt)
+        .take_while(|(cached, token)| cached == token)
+        .count()
+        .min(prompt.len().saturating_sub(1));
+    (kept, kept.saturating_sub(1))
+}
+
+// The context is only ever used behind the model's queue
+unsafe impl Send for PromptCache {}
+
+impl Drop for PromptCache {
+    fn drop(&mut self) {
+        unsafe { llama_free(self.ctx) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::reusable_prefix;
+
+    // Tokens start with the beginning-of-sentence token, as `tokenize` returns them
+    const BOS: i32 = 1;
+
+    #[test]
+    fn nothing_is_reused_from_an_empty_cache() {
+        assert_eq!(reusable_prefix(&[], &[BOS, 5, 6]), (0, 0));
+    }
+
+    #[test]
+    fn a_fully_cached_prompt_evaluates_its_last_token_again() {
+        assert_eq!(reusable_prefix(&[BOS, 5, 6], &[BOS, 5, 6]), (2, 1));
+        // The cache also holds the last response
+        assert_eq!(reusable_prefix(&[BOS, 5, 6, 7, 8], &[BOS, 5, 6]), (2, 1));
+    }
+
+    #[test]
+    fn nothing_is_reused_when_the_first_token_differs() {
+        assert_eq!(reusable_prefix(&[2, 5, 6], &[BOS, 5, 6]), (0, 0));
+    }
+
+    #[test]
+    fn a_shorter_prompt_keeps_the_prefix_it_shares() {
+        assert_eq!(reusable_prefix(&[BOS, 5, 6, 7], &[BOS, 5, 9]), (2, 1));
+        assert_eq!(reusable_prefix(&[BOS, 5, 6, 7], &[BOS, 5]), (1, 0));
+        assert_eq!(reusable_prefix(&[BOS, 5, 6, 7], &[BOS]), (0, 0));
+    }
+}
@@ -0,0 +1,286 @@
+use crate::backend::Backend;
+use crate::error::LLMError;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+// Reasons a request could not be given its turn on the LLM
+#[derive(Debug, Clone, Copy)]
+pub enum QueueError {
+    // The queue already holds the maximum number of waiting requests
+    Full { retry_after: u64 },
+    // The request waited longer than the configured timeout
+    TimedOut { retry_after: u64 },
+    // The model could not be loaded without exceeding the memory budget
+    OutOfMemory { retry_after: u64 },
+    // The model failed to load
+    LoadFailed,
+    // The model can't be loaded for embeddings, which take a second copy, within the memory budget
+    TooLarge,
+}
+
+impl QueueError {
+    // Suggested number of seconds a client should wait before retrying, if retrying may help
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            QueueError::Full { retry_after }
+            | QueueError::TimedOut { retry_after }
+            | QueueError::OutOfMemory { retry_after } => Some(*retry_after),
+            QueueError::LoadFailed | QueueError::TooLarge => None,
+        }
+    }
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueueError::Full { .. } => write!(f, "LLM queue is full"),
+            QueueError::TimedOut { .. } => write!(f, "Timed out waiting for the LLM"),
+            QueueError::OutOfMemory { .. } => write!(f, "Not enough memory to load the model"),
+            QueueError::LoadFailed => write!(f, "Failed to load the model"),
+            QueueError::TooLarge => write!(
+                f,
+                "The model is too large to be loaded a second time for embeddings within the memory budget"
+            ),
+        }
+    }
+}
+
+// The LLM a queue hands out, which is only loaded while it is needed
+type LLMSlot = Option<Box<dyn Backend>>;
+
+// A bounded FIFO queue of requests waiting for their turn on the LLM.
+// tokio's Mutex hands the lock out in the order it was requested, which makes the queue FIFO.
+pub struct RequestQueue {
+    llm: Arc<Mutex<LLMSlot>>,
+    // Mirrors how many copies of the model the slot holds, so it can be read without taking the lock:
+    // none until it is loaded, and two once it has also been loaded in embedding mode
+    copies: Arc<AtomicUsize>,
+    waiting: AtomicUsize,
+    max_depth: usize,
+    wait_timeout: Duration,
+    // Moving average of how long a request holds the LLM, used to estimate `Retry-After`
+    avg_hold_ms: Arc<AtomicU64>,
+    // When the LLM was last released, used to find idle models
+    last_used: Arc<std::sync::Mutex<Instant>>,
+}
+
+impl RequestQueue {
+    // Create a queue for an LLM which is loaded by the first request to acquire it
+    pub fn new(max_depth: usize, wait_timeout: Duration) -> Self {
+        Self {
+            llm: Arc::new(Mutex::new(None)),
+            copies: Arc::new(AtomicUsize::new(0)),
+            waiting: AtomicUsize::new(0),
+            max_depth,
+            wait_timeout,
+            avg_hold_ms: Arc::new(AtomicU64::new(0)),
+            last_used: Arc::new(std::sync::Mutex::new(Instant::now())),
+        }
+    }
+
+    // Wait for exclusive access to the LLM, calling `load` to load it first if it isn't loaded.
+    // Fails immediately if the queue is full, or once the wait timeout has elapsed.
+    pub async fn acquire<F>(&self, load: F) -> Result<QueueGuard, QueueError>
+    where
+        F: FnOnce() -> Result<Box<dyn Backend>, QueueError>,
+    {
+        let started = Instant::now();
+        let mut guard = self.lock().await?;
+        let waited = started.elapsed();
+        if guard.is_none() {
+            // Loading blocks for a while, so keep it off the async worker threads
+            *guard = Some(tokio::task::block_in_place(load)?);
+            self.copies.store(1, Ordering::SeqCst);
+        }
+        Ok(self.guard(guard, waited))
+    }
+
+    // Wait for exclusive access to the LLM only if it is already loaded, returning None if it isn't.
+    // This doesn't count as using the LLM, so it is still unloaded once the idle timeout passes.
+    pub async fn acquire_if_loaded(&self) -> Result<Option<QueueGuard>, QueueError> {
+        if !self.is_loaded() {
+            return Ok(None);
+        }
+        let started = Instant::now();
+        let guard = self.lock().await?;
+        if guard.is_none() {
+            return Ok(None);
+        }
+        let mut guard = self.guard(guard, started.elapsed());
+        guard.counts_as_use = false;
+        Ok(Some(guard))
+    }
+
+    // Swap in a new LLM once the requests queued ahead of this call are done,
+    // dropping the old one (if any)
+    pub async fn replace(&self, llm: Box<dyn Backend>) {
+        let mut slot = self.llm.lock().await;
+        *slot = Some(llm);
+        self.copies.store(1, Ordering::SeqCst);
+    }
+
+    // Unload the LLM if it is loaded and idle, returns whether it was unloaded
+    pub fn try_unload(&self) -> bool {
+        match self.llm.try_lock() {
+            Ok(mut slot) => {
+                let unloaded = slot.take().is_some();
+                self.copies.store(0, Ordering::SeqCst);
+                unloaded
+            }
+            Err(_) => false,
+        }
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.copies() > 0
+    }
+
+    // How many copies of the model are loaded
+    pub fn copies(&self) -> usize {
+        self.copies.load(Ordering::SeqCst)
+    }
+
+    // How long it has been since a request last released the LLM
+    pub fn idle_for(&self) -> Duration {
+        self.last_used.lock().unwrap().elapsed()
+    }
+
+    // Wait for the lock, skipping the queue entirely if the LLM is idle
+    async fn lock(&self) -> Result<OwnedMutexGuard<LLMSlot>, QueueError> {
+        if let Ok(guard) = Arc::clone(&self.llm).try_lock_owned() {
+            return Ok(guard);
+        }
+
+        // Reserve a place in the queue, or turn the request away if it is full
+        let slot = WaitingSlot::reserve(&self.waiting);
+        if slot.position >= self.max_depth {
+            return Err(QueueError::Full {
+                retry_after: self.retry_after(),
+            });
+        }
+
+        let res = tokio::time::timeout(self.wait_timeout, Arc::clone(&self.llm).lock_owned()).await;
+        drop(slot);
+        match res {
+            Ok(guard) => Ok(guard),
+            Err(_) => Err(QueueError::TimedOut {
+                retry_after: self.retry_after(),
+            }),
+        }
+    }
+
+    // Whether a request currently holds the LLM
+    pub fn is_busy(&self) -> bool {
+        self.llm.try_lock().is_err()
+    }
+
+    // The number of requests waiting for their turn
+    pub fn queued(&self) -> usize {
+        self.waiting.load(Ordering::SeqCst)
+    }
+
+    // Whether new requests would be turned away because the queue is full
+    pub fn is_saturated(&self) -> bool {
+        self.queued() >= self.max_depth
+    }
+
+    fn guard(&self, guard: OwnedMutexGuard<LLMSlot>, waited: Duration) -> QueueGuard {
+        QueueGuard {
+            guard,
+            waited,
+            acquired: Instant::now(),
+            copies: Arc::clone(&self.copies),
+            avg_hold_ms: Arc::clone(&self.avg_hold_ms),
+            last_used: Arc::clone(&self.last_used),
+            counts_as_use: true,
+        }
+    }
+
+    // Estimate how long it takes for everyone currently queued to be served
+    pub fn retry_after(&self) -> u64 {
+        let avg_hold_ms = self.avg_hold_ms.load(Ordering::Relaxed);
+        let estimate_ms = avg_hold_ms * (self.queued() as u64 + 1);
+        (estimate_ms / 1000).max(1)
+    }
+}
+
+// A place in the queue, given up once dropped (including when the waiting request is dropped)
+struct WaitingSlot<'a> {
+    waiting: &'a AtomicUsize,
+    position: usize,
+}
+
+impl<'a> WaitingSlot<'a> {
+    fn reserve(waiting: &'a AtomicUsize) -> Self {
+        let position = waiting.fetch_add(1, Ordering::SeqCst);
+        Self { waiting, position }
+    }
+}
+
+impl Drop for WaitingSlot<'_> {
+    fn drop(&mut self) {
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Exclusive access to the (loaded) LLM, released back to the queue once dropped
+pub struct QueueGuard {
+    guard: OwnedMutexGuard<LLMSlot>,
+    // How long the request waited for its turn, not counting loading the model
+    pub waited: Duration,
+    acquired: Instant,
+    copies: Arc<AtomicUsize>,
+    avg_hold_ms: Arc<AtomicU64>,
+    last_used: Arc<std::sync::Mutex<Instant>>,
+    // Whether releasing the LLM resets its idle time
+    counts_as_use: bool,
+}
+
+impl QueueGuard {
+    // Load the model a second time in embedding mode, if it isn't already
+    pub fn load_embeddings(&mut self) -> Result<(), LLMError> {
+        if !self.embeddings_loaded() {
+            Backend::load_embeddings(&mut **self)?;
+            self.copies.store(2, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+}
+
+// Guards are only handed out once the LLM has been loaded
+const NOT_LOADED: &str = "the LLM is loaded before access to it is handed out";
+
+impl Deref for QueueGuard {
+    type Target = dyn Backend;
+
+    fn deref(&self) -> &Self::Target {
+        self.guard.as_deref().expect(NOT_LOADED)
+    }
+}
+
+impl DerefMut for QueueGuard {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.guard.as_deref_mut().expect(NOT_LOADED)
+    }
+}
+
+impl Drop for QueueGuard {
+    fn drop(&mut self) {
+        // Fold this request's hold time into the moving average
+        let held_ms = self.acquired.elapsed().as_millis() as u64;
+        let avg_ms = self.avg_hold_ms.load(Ordering::Relaxed);
+        let new_avg_ms = if avg_ms == 0 {
+            held_ms
+        } else {
+            (avg_ms * 7 + held_ms) / 8
+        };
+        self.avg_hold_ms.store(new_avg_ms, Ordering::Relaxed);
+        if self.counts_as_use {
+            *self.last_used.lock().unwrap() = Instant::now();
+        }
+    }
+}
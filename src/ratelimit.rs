@@ -0,0 +1,278 @@
+use crate::endpoints::route_requests;
+use crate::error::LLMError;
+use crate::keyring::ApiKey;
+use crate::openai;
+use crate::responses::error_response;
+use crate::state::ServerState;
+use hyper::header::{HeaderMap, HeaderValue};
+use hyper::{header, Body, Request, Response, StatusCode};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const MINUTE: Duration = Duration::from_secs(60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+// How much a client may use the server, unlimited where not set
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimits {
+    pub requests_per_minute: Option<u32>,
+    pub tokens_per_day: Option<u64>,
+}
+
+impl RateLimits {
+    // Fill in the limits which are not set from `defaults`
+    fn or(self, defaults: RateLimits) -> Self {
+        Self {
+            requests_per_minute: self.requests_per_minute.or(defaults.requests_per_minute),
+            tokens_per_day: self.tokens_per_day.or(defaults.tokens_per_day),
+        }
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.requests_per_minute.is_none() && self.tokens_per_day.is_none()
+    }
+}
+
+// Who a request is counted against: its API key, or else the address it came from
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ClientId {
+    Key(String),
+    Ip(IpAddr),
+}
+
+// Which limit a client ran into
+#[derive(Debug, Clone, Copy)]
+enum Limit {
+    Requests,
+    Tokens,
+}
+
+// A request which was over one of its client's limits
+#[derive(Debug, Clone, Copy)]
+pub struct LimitExceeded {
+    limit: Limit,
+    budget: Budget,
+}
+
+impl LimitExceeded {
+    // Number of seconds until the limit resets
+    pub fn retry_after(&self) -> u64 {
+        match self.limit {
+            Limit::Requests => self.budget.requests_reset,
+            Limit::Tokens => self.budget.tokens_reset,
+        }
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.limit {
+            Limit::Requests => write!(f, "Request rate limit exceeded"),
+            Limit::Tokens => write!(f, "Daily token quota exceeded"),
+        }
+    }
+}
+
+// What a client has left, as of the start of a request
+#[derive(Debug, Clone, Copy)]
+pub struct Budget {
+    limits: RateLimits,
+    requests_remaining: u32,
+    requests_reset: u64,
+    tokens_remaining: u64,
+    tokens_reset: u64,
+}
+
+impl Budget {
+    // Adds the `X-RateLimit-*` headers for each limit the client has
+    fn apply(&self, headers: &mut HeaderMap) {
+        let mut insert = |name: &'static str, value: u64| {
+            headers.insert(name, HeaderValue::from(value));
+        };
+        if let Some(limit) = self.limits.requests_per_minute {
+            insert("x-ratelimit-limit-requests", limit.into());
+            insert(
+                "x-ratelimit-remaining-requests",
+                self.requests_remaining.into(),
+            );
+            insert("x-ratelimit-reset-requests", self.requests_reset);
+        }
+        if let Some(limit) = self.limits.tokens_per_day {
+            insert("x-ratelimit-limit-tokens", limit);
+            insert("x-ratelimit-remaining-tokens", self.tokens_remaining);
+            insert("x-ratelimit-reset-tokens", self.tokens_reset);
+        }
+    }
+}
+
+// What a client has used in the current minute and day
+struct Usage {
+    minute_start: Instant,
+    requests: u32,
+    day_start: Instant,
+    tokens: u64,
+}
+
+// A client with limits, and what it has used of them
+pub struct Client {
+    limits: RateLimits,
+    usage: Mutex<Usage>,
+}
+
+impl Client {
+    fn new(limits: RateLimits) -> Self {
+        let now = Instant::now();
+        Self {
+            limits,
+            usage: Mutex::new(Usage {
+                minute_start: now,
+                requests: 0,
+                day_start: now,
+                tokens: 0,
+            }),
+        }
+    }
+
+    // Count a new request against the client's limits, failing if it is over either of them
+    fn start_request(&self) -> Result<Budget, LimitExceeded> {
+        let mut usage = self.usage.lock().unwrap();
+        let now = Instant::now();
+        if now.duration_since(usage.minute_start) >= MINUTE {
+            usage.minute_start = now;
+            usage.requests = 0;
+        }
+        if now.duration_since(usage.day_start) >= DAY {
+            usage.day_start = now;
+            usage.tokens = 0;
+        }
+
+        let limit = match (self.limits.tokens_per_day, self.limits.requests_per_minute) {
+            (Some(quota), _) if usage.tokens >= quota => Some(Limit::Tokens),
+            (_, Some(limit)) if usage.requests >= limit => Some(Limit::Requests),
+            _ => {
+                usage.requests += 1;
+                None
+            }
+        };
+        let budget = Budget {
+            limits: self.limits,
+            requests_remaining: self
+                .limits
+                .requests_per_minute
+                .map(|limit| limit.saturating_sub(usage.requests))
+                .unwrap_or(0),
+            requests_reset: seconds_left(usage.minute_start, MINUTE),
+            tokens_remaining: self
+                .limits
+                .tokens_per_day
+                .map(|quota| quota.saturating_sub(usage.tokens))
+                .unwrap_or(0),
+            tokens_reset: seconds_left(usage.day_start, DAY),
+        };
+        match limit {
+            Some(limit) => Err(LimitExceeded { limit, budget }),
+            None => Ok(budget),
+        }
+    }
+
+    // Count generated tokens against the client's daily quota
+    pub fn record_tokens(&self, tokens: usize) {
+        self.usage.lock().unwrap().tokens += tokens as u64;
+    }
+
+    // Whether forgetting the client would lose track of anything it used
+    fn is_active(&self) -> bool {
+        let usage = self.usage.lock().unwrap();
+        usage.minute_start.elapsed() < MINUTE
+            || (usage.tokens > 0 && usage.day_start.elapsed() < DAY)
+    }
+}
+
+// Tracks what every client has used, so no single one can monopolize the models
+pub struct RateLimiter {
+    // The limits of clients without an API key, and of keys without their own
+    defaults: RateLimits,
+    clients: Mutex<HashMap<ClientId, Arc<Client>>>,
+    last_prune: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(defaults: RateLimits) -> Self {
+        Self {
+            defaults,
+            clients: Mutex::new(HashMap::new()),
+            last_prune: Mutex::new(Instant::now()),
+        }
+    }
+
+    // Look up a client, or None if it has no limits
+    fn client(&self, id: ClientId, limits: RateLimits) -> Option<Arc<Client>> {
+        let limits = limits.or(self.defaults);
+        if limits.is_unlimited() {
+            return None;
+        }
+        let mut clients = self.clients.lock().unwrap();
+        self.prune(&mut clients);
+        let client = clients
+            .entry(id)
+            .or_insert_with(|| Arc::new(Client::new(limits)));
+        Some(Arc::clone(client))
+    }
+
+    // Forget clients which have not used anything recently, at most once a minute,
+    // so clients coming from ever changing addresses don't add up
+    fn prune(&self, clients: &mut HashMap<ClientId, Arc<Client>>) {
+        let mut last_prune = self.last_prune.lock().unwrap();
+        if last_prune.elapsed() < MINUTE {
+            return;
+        }
+        *last_prune = Instant::now();
+        clients.retain(|_, client| Arc::strong_count(client) > 1 || client.is_active());
+    }
+}
+
+// Counts every request against its client's limits before it reaches the endpoints,
+// and lets the client know what it has left through the `X-RateLimit-*` headers
+pub async fn rate_limit_layer(
+    mut req: Request<Body>,
+    state: Arc<ServerState>,
+    remote_addr: SocketAddr,
+) -> Result<Response<Body>, LLMError> {
+    let (id, limits) = match req.extensions().get::<Arc<ApiKey>>() {
+        Some(key) => (ClientId::Key(key.name.clone()), key.limits),
+        None => (ClientId::Ip(remote_addr.ip()), RateLimits::default()),
+    };
+    let client = match state.rate_limiter.client(id, limits) {
+        Some(client) => client,
+        None => return route_requests(req, state).await,
+    };
+
+    let budget = match client.start_request() {
+        Ok(budget) => budget,
+        Err(e) => {
+            // OpenAI clients expect their own error format
+            let message = e.to_string();
+            let mut res = if req.uri().path().starts_with("/v1/") {
+                openai::error_response(StatusCode::TOO_MANY_REQUESTS, &message)?
+            } else {
+                error_response(StatusCode::TOO_MANY_REQUESTS, &message)?
+            };
+            e.budget.apply(res.headers_mut());
+            res.headers_mut()
+                .insert(header::RETRY_AFTER, e.retry_after().into());
+            return Ok(res);
+        }
+    };
+    // Let the endpoints record the tokens they generate
+    req.extensions_mut().insert(client);
+    let mut res = route_requests(req, state).await?;
+    budget.apply(res.headers_mut());
+    Ok(res)
+}
+
+fn seconds_left(start: Instant, window: Duration) -> u64 {
+    window.saturating_sub(start.elapsed()).as_secs().max(1)
+}
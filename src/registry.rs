@@ -0,0 +1,346 @@
+use crate::backend::{Backend, BackendKind};
+use crate::error::LLMError;
+use crate::llm_interface::LLMInterface;
+use crate::metrics::Metrics;
+use crate::mock::MockBackend;
+use crate::queue::{QueueError, QueueGuard, RequestQueue};
+use crate::sampling::{Sampling, SamplingLimits, SamplingParams};
+use crate::templates::ChatTemplate;
+use llm_chain_llama::PerInvocation;
+use std::cmp::Reverse;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tracing::{error, info};
+
+// A model given on the command line, as either `path` or `name=path`
+#[derive(Debug, Clone)]
+pub struct ModelSpec {
+    pub name: String,
+    pub path: String,
+    // The name of the chat template the model uses, if not the default one
+    pub template: Option<String>,
+}
+
+impl ModelSpec {
+    pub fn parse(spec: &str) -> Self {
+        match spec.split_once('=') {
+            Some((name, path)) if !name.is_empty() => Self {
+                name: name.to_string(),
+                path: path.to_string(),
+                template: None,
+            },
+            _ => Self {
+                name: model_name_from_path(spec),
+                path: spec.to_string(),
+                template: None,
+            },
+        }
+    }
+}
+
+// The default name of a model, derived from its file name
+pub fn model_name_from_path(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string())
+}
+
+// The settings every model is loaded with
+#[derive(Debug, Clone)]
+pub struct ModelSettings {
+    pub num_threads: u16,
+    pub temp: f32,
+    pub freq_penalty: f32,
+    pub output_tokens: usize,
+    pub limits: SamplingLimits,
+    // Whether prompt text is written to the log
+    pub log_prompts: bool,
+    pub backend: BackendKind,
+}
+
+impl ModelSettings {
+    // Sampling options used for any parameter a request does not override
+    pub fn default_options(&self) -> PerInvocation {
+        let mut options = PerInvocation::new();
+        options.n_threads = Some(self.num_threads as i32);
+        options.temp = Some(self.temp);
+        options.repeat_penalty = Some(self.freq_penalty);
+        options.n_tok_predict = Some(self.output_tokens);
+        options
+    }
+}
+
+// The file a model is loaded from
+#[derive(Debug, Clone)]
+struct ModelFile {
+    path: String,
+    // Approximate memory used while loaded, in bytes (the model file is memory mapped)
+    size: u64,
+}
+
+impl ModelFile {
+    fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            size: fs::metadata(path).map(|m| m.len()).unwrap_or(0),
+        }
+    }
+}
+
+// A model being served, with its own queue (and so its own lock).
+// The model itself is only loaded while it is in use.
+pub struct Model {
+    pub name: String,
+    // Changes when the model is reloaded from another file
+    file: RwLock<ModelFile>,
+    pub settings: ModelSettings,
+    // Renders chat messages into the model's prompt format
+    pub template: Arc<ChatTemplate>,
+    pub queue: RequestQueue,
+}
+
+impl Model {
+    pub fn new(
+        spec: &ModelSpec,
+        template: Arc<ChatTemplate>,
+        settings: ModelSettings,
+        queue: RequestQueue,
+    ) -> Self {
+        Self {
+            name: spec.name.clone(),
+            file: RwLock::new(ModelFile::new(&spec.path)),
+            settings,
+            template,
+            queue,
+        }
+    }
+
+    pub fn path(&self) -> String {
+        self.file.read().unwrap().path.clone()
+    }
+
+    // Validate per-request sampling parameters against the server defaults and limits.
+    // This needs no access to the model, so requests are validated before they are queued.
+    pub fn sampling(&self, params: &SamplingParams) -> Result<Sampling, LLMError> {
+        params.resolve(&self.settings.default_options(), &self.settings.limits)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.file.read().unwrap().size
+    }
+
+    // Approximate memory used by every copy of the model which is loaded, in bytes
+    pub fn resident_size(&self) -> u64 {
+        self.size() * self.queue.copies() as u64
+    }
+
+    fn load(&self, file: &ModelFile) -> Result<Box<dyn Backend>, LLMError> {
+        if let BackendKind::Mock(mock) = &self.settings.backend {
+            let mock = MockBackend::new(&self.name, &self.settings, mock.clone());
+            return Ok(Box::new(mock));
+        }
+        let mut llm = LLMInterface::new_local_llm(&file.path, self.settings.num_threads)?;
+        llm.name = self.name.clone();
+        llm.log_prompts = self.settings.log_prompts;
+        Ok(Box::new(llm))
+    }
+}
+
+// What a request needs a model loaded for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Usage {
+    Generation,
+    // Embeddings need the model loaded a second time, in embedding mode
+    Embeddings,
+}
+
+// Every model being served, in the order they were given.
+// The first one is used by requests which don't name a model.
+pub struct ModelRegistry {
+    models: Vec<Model>,
+    // The most memory loaded models may use together, in bytes
+    memory_budget: Option<u64>,
+    // Models are loaded one at a time, so the memory budget is checked consistently
+    loading: Mutex<()>,
+    metrics: Arc<Metrics>,
+}
+
+impl ModelRegistry {
+    pub fn new(memory_budget: Option<u64>, metrics: Arc<Metrics>) -> Self {
+        Self {
+            models: Vec::new(),
+            memory_budget,
+            loading: Mutex::new(()),
+            metrics,
+        }
+    }
+
+    // Add a model, failing if its name is already taken or it can never fit in the budget
+    pub fn add(&mut self, model: Model) -> Result<(), String> {
+        if self.models.iter().any(|m| m.name == model.name) {
+            return Err(format!(
+                "Model name `{}` is used more than once",
+                model.name
+            ));
+        }
+        if let Some(budget) = self.memory_budget {
+            if model.size() > budget {
+                return Err(format!(
+                    "Model `{}` is larger than the memory budget",
+                    model.name
+                ));
+            }
+        }
+        self.models.push(model);
+        Ok(())
+    }
+
+    // Look up a model by name, or the default model if no name is given
+    pub fn get(&self, name: Option<&str>) -> Option<&Model> {
+        match name {
+            Some(name) => self.models.iter().find(|m| m.name == name),
+            None => self.models.first(),
+        }
+    }
+
+    pub fn default_model(&self) -> Option<&Model> {
+        self.models.first()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Model> {
+        self.models.iter()
+    }
+
+    // Wait for exclusive access to a model, loading it first if needed
+    pub async fn acquire(&self, model: &Model, usage: Usage) -> Result<QueueGuard, QueueError> {
+        let mut guard = model
+            .queue
+            .acquire(|| {
+                let file = model.file.read().unwrap().clone();
+                self.load(model, &file)
+            })
+            .await?;
+        if usage == Usage::Embeddings && !guard.embeddings_loaded() {
+            tokio::task::block_in_place(|| self.load_embeddings(model, &mut guard))?;
+        }
+        self.metrics.record_queue_wait(guard.waited);
+        Ok(guard)
+    }
+
+    // Load a new copy of a model, from `path` or else the file it was last loaded from,
+    // and swap it in once the requests queued ahead of the swap are done with the old copy.
+    // The old copy keeps serving requests while the new one loads.
+    pub async fn reload(&self, model: &Model, path: Option<&str>) -> Result<(), QueueError> {
+        let file = match path {
+            Some(path) => ModelFile::new(path),
+            None => model.file.read().unwrap().clone(),
+        };
+        let llm = tokio::task::block_in_place(|| self.load(model, &file))?;
+        // Anyone loading the model from here on gets the new file too
+        *model.file.write().unwrap() = file;
+        model.queue.replace(llm).await;
+        info!(model = %model.name, "Reloaded model");
+        Ok(())
+    }
+
+    // Reload every loaded model from its file, ie. after the files were replaced on disk.
+    // Models which are not loaded pick up the new files the next time they are used anyways.
+    pub async fn reload_all(&self) {
+        for model in self.models.iter().filter(|m| m.queue.is_loaded()) {
+            if let Err(e) = self.reload(model, None).await {
+                error!(model = %model.name, error = %e, "Failed to reload model");
+            }
+        }
+    }
+
+    // Load a model from the given file, making room for it in the memory budget first
+    fn load(&self, model: &Model, file: &ModelFile) -> Result<Box<dyn Backend>, QueueError> {
+        let _loading = self.loading.lock().unwrap();
+        self.make_room_for(file.size)?;
+        info!(model = %model.name, path = %file.path, "Loading model");
+        let started = Instant::now();
+        let llm = model.load(file).map_err(|_| QueueError::LoadFailed)?;
+        let took = started.elapsed();
+        info!(
+            model = %model.name,
+            took_ms = took.as_millis() as u64,
+            "Loaded model"
+        );
+        self.metrics.record_model_load(took);
+        Ok(llm)
+    }
+
+    // Load the model held by `llm` a second time in embedding mode,
+    // making room for the second copy in the memory budget first
+    fn load_embeddings(&self, model: &Model, llm: &mut QueueGuard) -> Result<(), QueueError> {
+        // Models only need to fit in the budget once to be added, so this one may never fit twice
+        if self
+            .memory_budget
+            .is_some_and(|budget| model.size().saturating_mul(2) > budget)
+        {
+            return Err(QueueError::TooLarge);
+        }
+        let _loading = self.loading.lock().unwrap();
+        // The model's first copy is held by the request, so it is counted but never unloaded
+        self.make_room_for(model.size())?;
+        info!(model = %model.name, "Loading model in embedding mode");
+        let started = Instant::now();
+        llm.load_embeddings().map_err(|_| QueueError::LoadFailed)?;
+        self.metrics.record_model_load(started.elapsed());
+        Ok(())
+    }
+
+    // Unload idle models, least recently used first, until `size` more bytes fit in the memory budget.
+    // A model being reloaded is counted too, as its old copy stays loaded until the new one is swapped in.
+    fn make_room_for(&self, size: u64) -> Result<(), QueueError> {
+        let budget = match self.memory_budget {
+            Some(budget) => budget,
+            None => return Ok(()),
+        };
+        let mut loaded: Vec<&Model> = self.models.iter().filter(|m| m.queue.is_loaded()).collect();
+        let mut used: u64 = loaded.iter().map(|m| m.resident_size()).sum();
+        loaded.sort_by_key(|m| Reverse(m.queue.idle_for()));
+
+        for other in &loaded {
+            if used + size <= budget {
+                break;
+            }
+            // Loading holds `loading`, so no copy can be added between reading the size and unloading
+            let freed = other.resident_size();
+            if other.queue.try_unload() {
+                info!(
+                    model = %other.name,
+                    "Unloaded model to stay within the memory budget"
+                );
+                used -= freed;
+            }
+        }
+        if used + size <= budget {
+            return Ok(());
+        }
+
+        // Whatever is still loaded is in use, so suggest retrying once the first of them is done
+        let retry_after = loaded
+            .iter()
+            .filter(|m| m.queue.is_loaded())
+            .map(|m| m.queue.retry_after())
+            .min()
+            .unwrap_or(1);
+        Err(QueueError::OutOfMemory { retry_after })
+    }
+
+    // Unload every model which has not been used for `idle_timeout`
+    pub fn unload_idle(&self, idle_timeout: Duration) {
+        for model in &self.models {
+            if model.queue.is_loaded()
+                && model.queue.idle_for() >= idle_timeout
+                && model.queue.try_unload()
+            {
+                info!(model = %model.name, "Unloaded idle model");
+            }
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use crate::error::LLMError;
+use hyper::{header, Body, Response, StatusCode};
+use serde::Serialize;
+
+// Struct to represent a submit prompt response.
+// Failed requests to the rest of the (non-OpenAI) API are reported in the same shape.
+#[derive(Serialize)]
+pub struct PromptResponse {
+    pub success: bool,
+    pub response: String,
+}
+
+// Serializes a value into a JSON response with the given status
+pub fn json_response<T: Serialize>(
+    status: StatusCode,
+    value: &T,
+) -> Result<Response<Body>, LLMError> {
+    let body = serde_json::to_string(value)?;
+    Ok(Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))?)
+}
+
+// Builds a JSON response in the shape of a prompt response
+pub fn prompt_response(
+    status: StatusCode,
+    success: bool,
+    message: &str,
+) -> Result<Response<Body>, LLMError> {
+    json_response(
+        status,
+        &PromptResponse {
+            success,
+            response: message.to_string(),
+        },
+    )
+}
+
+// Builds the response for a failed request, in the shape of a prompt response
+pub fn error_response(status: StatusCode, message: &str) -> Result<Response<Body>, LLMError> {
+    prompt_response(status, false, message)
+}
@@ -0,0 +1,130 @@
+use crate::error::LLMError;
+use llm_chain_llama::PerInvocation;
+use serde::{Deserialize, Serialize};
+
+// Sampling parameters a client may override per request.
+// Anything left unset falls back to the defaults the server was started with.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SamplingParams {
+    pub temp: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<i32>,
+    pub repeat_penalty: Option<f32>,
+    pub n_tok_predict: Option<usize>,
+    pub seed: Option<i32>,
+    pub stop: Option<Vec<String>>,
+}
+
+// Server-side limits which client provided sampling parameters are clamped to
+#[derive(Debug, Clone)]
+pub struct SamplingLimits {
+    pub max_temp: f32,
+    pub max_top_k: i32,
+    pub max_repeat_penalty: f32,
+    pub max_output_tokens: usize,
+    pub max_stop_sequences: usize,
+}
+
+// The validated sampling configuration of a single generation
+#[derive(Debug, Clone, Default)]
+pub struct Sampling {
+    pub options: PerInvocation,
+    pub seed: Option<i32>,
+    pub stop: Vec<String>,
+}
+
+impl SamplingParams {
+    // Validate the parameters and merge them on top of the defaults, clamping to the limits
+    pub fn resolve(
+        &self,
+        defaults: &PerInvocation,
+        limits: &SamplingLimits,
+    ) -> Result<Sampling, LLMError> {
+        let mut options = defaults.clone();
+
+        if let Some(temp) = self.temp {
+            if !temp.is_finite() || temp < 0.0 {
+                return Err(invalid("temp must be a non-negative number"));
+            }
+            options.temp = Some(temp.min(limits.max_temp));
+        }
+        if let Some(top_p) = self.top_p {
+            if !top_p.is_finite() || top_p <= 0.0 || top_p > 1.0 {
+                return Err(invalid("top_p must be greater than 0 and at most 1"));
+            }
+            options.top_p = Some(top_p);
+        }
+        if let Some(top_k) = self.top_k {
+            if top_k < 1 {
+                return Err(invalid("top_k must be at least 1"));
+            }
+            options.top_k = Some(top_k.min(limits.max_top_k));
+        }
+        if let Some(repeat_penalty) = self.repeat_penalty {
+            if !repeat_penalty.is_finite() || repeat_penalty <= 0.0 {
+                return Err(invalid("repeat_penalty must be greater than 0"));
+            }
+            options.repeat_penalty = Some(repeat_penalty.min(limits.max_repeat_penalty));
+        }
+        if let Some(n_tok_predict) = self.n_tok_predict {
+            if n_tok_predict < 1 {
+                return Err(invalid("n_tok_predict must be at least 1"));
+            }
+            options.n_tok_predict = Some(n_tok_predict.min(limits.max_output_tokens));
+        }
+        if let Some(seed) = self.seed {
+            // llama.cpp treats non-positive seeds as "seed from the current time"
+            if seed < 1 {
+                return Err(invalid("seed must be a positive integer"));
+            }
+        }
+
+        let stop = self.stop.clone().unwrap_or_default();
+        if stop.len() > limits.max_stop_sequences {
+            return Err(invalid(&format!(
+                "at most {} stop sequences are allowed",
+                limits.max_stop_sequences
+            )));
+        }
+        if stop.iter().any(|s| s.is_empty()) {
+            return Err(invalid("stop sequences must not be empty"));
+        }
+        // Generations stop at a blank line unless given a stop sequence of their own
+        if let Some(first) = stop.first() {
+            options.stop_sequence = Some(first.clone());
+        }
+
+        Ok(Sampling {
+            options,
+            seed: self.seed,
+            stop,
+        })
+    }
+}
+
+impl Sampling {
+    // Cut the generated text off at the first occurrence of any stop sequence
+    pub fn apply_stop_sequences(&self, text: &mut String) {
+        let cutoff = self.stop.iter().filter_map(|s| text.find(s.as_str())).min();
+        if let Some(cutoff) = cutoff {
+            text.truncate(cutoff);
+        }
+    }
+
+    // Also stop at the given sequences, which are not counted against the request's limit
+    pub fn add_stop_sequences(&mut self, stop: &[String]) {
+        self.stop.extend(stop.iter().cloned());
+        if self.options.stop_sequence.is_none() {
+            self.options.stop_sequence = self.stop.first().cloned();
+        }
+    }
+
+    // The maximum number of tokens this generation may produce (0 means unlimited)
+    pub fn max_tokens(&self) -> usize {
+        self.options.n_tok_predict.unwrap_or(0)
+    }
+}
+
+fn invalid(message: &str) -> LLMError {
+    LLMError::InvalidParameters(message.to_string())
+}
@@ -0,0 +1,430 @@
+use crate::backend::Backend;
+use crate::endpoints::{model_forbidden_response, model_not_found_response, spawn_on_model};
+use crate::error::LLMError;
+use crate::keyring::ApiKey;
+use crate::queue::QueueGuard;
+use crate::registry::Usage;
+use crate::responses::{error_response, json_response};
+use crate::sampling::{Sampling, SamplingParams};
+use crate::state::ServerState;
+use crate::templates::{ChatMessage, ChatTemplate};
+use hyper::body::to_bytes;
+use hyper::{Body, Request, Response};
+use hyper::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::oneshot;
+use tracing::warn;
+use uuid::Uuid;
+
+// A conversation whose history is kept by the server, so clients only send their new messages
+struct Session {
+    model: String,
+    // The name of the API key which created the session, as only it may use the session
+    owner: Option<String>,
+    system: Option<String>,
+    // The messages so far, less those the last prompt had to leave out to fit the context window
+    messages: Vec<ChatMessage>,
+    last_used: Instant,
+    // Set while a message is being replied to, as the next one needs the reply in its history
+    busy: bool,
+}
+
+// Struct to represent create session input
+#[derive(Deserialize, Debug, Default)]
+struct CreateSessionInput {
+    // The model to chat with, the default model if not given
+    model: Option<String>,
+    // The system message every prompt of the session starts with
+    system: Option<String>,
+}
+
+// Struct to represent session message input
+#[derive(Deserialize, Debug)]
+struct MessageInput {
+    content: String,
+    // Optional per-request overrides of the sampling parameters
+    #[serde(flatten)]
+    sampling: SamplingParams,
+}
+
+// Struct to represent a session and its history
+#[derive(Serialize)]
+struct SessionResponse {
+    success: bool,
+    session_id: String,
+    model: String,
+    system: Option<String>,
+    messages: Vec<ChatMessage>,
+}
+
+// Struct to represent the reply to a session message
+#[derive(Serialize)]
+struct MessageResponse {
+    success: bool,
+    response: String,
+    // How many of the oldest messages were left out of the prompt to fit the context window
+    truncated: usize,
+}
+
+// Holds every session, until `ttl` has passed since it was last used
+pub struct SessionStore {
+    sessions: std::sync::Mutex<HashMap<String, Session>>,
+    ttl: Duration,
+}
+
+impl SessionStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            sessions: std::sync::Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    // Add a new session and return its id, dropping any expired sessions along the way
+    fn insert(&self, model: String, owner: Option<String>, system: Option<String>) -> String {
+        let id = Uuid::new_v4().simple().to_string();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| !self.is_expired(session));
+        sessions.insert(
+            id.clone(),
+            Session {
+                model,
+                owner,
+                system,
+                messages: Vec::new(),
+                last_used: Instant::now(),
+                busy: false,
+            },
+        );
+        id
+    }
+
+    fn is_expired(&self, session: &Session) -> bool {
+        !session.busy && session.last_used.elapsed() >= self.ttl
+    }
+
+    // Run `f` on a session which has not expired, if the key may use it.
+    // Sessions of other keys are treated as missing, so their ids can't be probed.
+    fn with_session<T>(
+        &self,
+        id: &str,
+        owner: Option<&str>,
+        f: impl FnOnce(&mut Session) -> T,
+    ) -> Option<T> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(id)?;
+        if self.is_expired(session) {
+            sessions.remove(id);
+            return None;
+        }
+        if session.owner.as_deref() != owner {
+            return None;
+        }
+        Some(f(session))
+    }
+
+    fn get(&self, id: &str, owner: Option<&str>) -> Option<SessionResponse> {
+        self.with_session(id, owner, |session| Self::response(id, session))
+    }
+
+    fn remove(&self, id: &str, owner: Option<&str>) -> Option<SessionResponse> {
+        let session = self.get(id, owner)?;
+        self.sessions.lock().unwrap().remove(id);
+        Some(session)
+    }
+
+    // Mark the session as replying to a message, and return the session as it was
+    fn start_turn(
+        &self,
+        id: &str,
+        owner: Option<&str>,
+    ) -> Result<SessionResponse, (StatusCode, &'static str)> {
+        self.with_session(id, owner, |session| {
+            if session.busy {
+                return Err((
+                    StatusCode::CONFLICT,
+                    "Session is still replying to another message",
+                ));
+            }
+            session.busy = true;
+            Ok(Self::response(id, session))
+        })
+        .unwrap_or(Err((StatusCode::NOT_FOUND, "Session not found")))
+    }
+
+    // Add a message and its reply to the session's history, dropping the oldest `truncated`
+    // messages which were left out of the prompt, so the history can't grow without limit.
+    // The session is busy while the reply is generated, so those are still the same messages.
+    fn append(&self, id: &str, truncated: usize, message: ChatMessage, reply: ChatMessage) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(id) {
+            session
+                .messages
+                .drain(..truncated.min(session.messages.len()));
+            session.messages.push(message);
+            session.messages.push(reply);
+        }
+    }
+
+    // Let the session take its next message, whether or not the last one got a reply
+    fn finish_turn(&self, id: &str) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(id) {
+            session.busy = false;
+            session.last_used = Instant::now();
+        }
+    }
+
+    fn response(id: &str, session: &Session) -> SessionResponse {
+        SessionResponse {
+            success: true,
+            session_id: id.to_string(),
+            model: session.model.clone(),
+            system: session.system.clone(),
+            messages: session.messages.clone(),
+        }
+    }
+}
+
+// A message being replied to, which keeps its session busy until dropped
+struct Turn {
+    state: Arc<ServerState>,
+    session_id: String,
+    system: Option<String>,
+    // The session's history, followed by the new message
+    messages: Vec<ChatMessage>,
+    template: Arc<ChatTemplate>,
+    // Validated before the turn is queued, with the template's stop sequences added
+    sampling: Sampling,
+}
+
+impl Turn {
+    // Render the prompt, leaving out the oldest messages until it fits in the context window
+    // with room for the reply. Returns the prompt and the number of messages left out.
+    fn fit_prompt(&self, llm: &dyn Backend) -> Result<(String, usize), LLMError> {
+        let context_size = llm.context_size();
+        // Keep up to half of the context for the reply, or less if the request asks for less
+        let reserved = match self.sampling.max_tokens() {
+            0 => context_size / 2,
+            max_tokens => max_tokens.min(context_size / 2),
+        };
+        let budget = context_size - reserved;
+        let system: Vec<ChatMessage> = self
+            .system
+            .iter()
+            .map(|content| ChatMessage {
+                role: "system".to_string(),
+                content: content.clone(),
+            })
+            .collect();
+        // Prompts start at a user message, so the conversation still reads in turns
+        for start in (0..self.messages.len()).filter(|&i| self.messages[i].role == "user") {
+            let messages: Vec<ChatMessage> = system
+                .iter()
+                .chain(&self.messages[start..])
+                .cloned()
+                .collect();
+            let prompt = self.template.render(&messages);
+            if llm.count_tokens(&prompt)? <= budget {
+                return Ok((prompt, start));
+            }
+        }
+        Err(LLMError::InvalidParameters(
+            "the message does not fit in the model's context window".to_string(),
+        ))
+    }
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        self.state.sessions.finish_turn(&self.session_id);
+    }
+}
+
+// Create a session (POST)
+pub async fn create_session_endpoint(
+    mut req: Request<Body>,
+    state: Arc<ServerState>,
+) -> Result<Response<Body>, LLMError> {
+    if req.method() != Method::POST {
+        return error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+    }
+
+    // Every field is optional, so an empty body creates a session with the default model
+    let body_bytes = to_bytes(req.body_mut()).await.unwrap_or_default();
+    let input: CreateSessionInput = if body_bytes.iter().all(u8::is_ascii_whitespace) {
+        CreateSessionInput::default()
+    } else {
+        match serde_json::from_slice(&body_bytes) {
+            Ok(input) => input,
+            Err(_) => {
+                return error_response(StatusCode::BAD_REQUEST, "Failed to parse request body")
+            }
+        }
+    };
+
+    let model = match state.models.get(input.model.as_deref()) {
+        Some(model) => model,
+        None => {
+            return model_not_found_response(req.uri().path(), &input.model.unwrap_or_default())
+        }
+    };
+    let key = req.extensions().get::<Arc<ApiKey>>();
+    if let Some(key) = key {
+        if !key.allows_model(&model.name) {
+            return model_forbidden_response(req.uri().path(), &model.name);
+        }
+    }
+
+    let owner = key.map(|key| key.name.clone());
+    let id = state
+        .sessions
+        .insert(model.name.clone(), owner.clone(), input.system);
+    match state.sessions.get(&id, owner.as_deref()) {
+        Some(session) => json_response(StatusCode::CREATED, &session),
+        None => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to create session",
+        ),
+    }
+}
+
+// Get a session's history (GET) or delete it (DELETE),
+// or send it a message and get the reply (POST to `/sessions/{id}/messages`)
+pub async fn session_endpoint(
+    req: Request<Body>,
+    state: Arc<ServerState>,
+) -> Result<Response<Body>, LLMError> {
+    let path = req.uri().path().trim_start_matches("/sessions/");
+    let owner = req
+        .extensions()
+        .get::<Arc<ApiKey>>()
+        .map(|key| key.name.clone());
+    if let Some(id) = path.strip_suffix("/messages") {
+        let id = id.to_string();
+        return match *req.method() {
+            Method::POST => message_endpoint(req, state, id, owner).await,
+            _ => error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"),
+        };
+    }
+    match *req.method() {
+        Method::GET => match state.sessions.get(path, owner.as_deref()) {
+            Some(session) => json_response(StatusCode::OK, &session),
+            None => error_response(StatusCode::NOT_FOUND, "Session not found"),
+        },
+        Method::DELETE => match state.sessions.remove(path, owner.as_deref()) {
+            Some(session) => json_response(StatusCode::OK, &session),
+            None => error_response(StatusCode::NOT_FOUND, "Session not found"),
+        },
+        _ => error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"),
+    }
+}
+
+// Add a message to a session, and reply to it with the session's history as context
+async fn message_endpoint(
+    mut req: Request<Body>,
+    state: Arc<ServerState>,
+    id: String,
+    owner: Option<String>,
+) -> Result<Response<Body>, LLMError> {
+    let body_bytes = to_bytes(req.body_mut()).await.unwrap_or_default();
+    let input: MessageInput = match serde_json::from_slice(&body_bytes) {
+        Ok(input) => input,
+        Err(_) => return error_response(StatusCode::BAD_REQUEST, "Failed to parse request body"),
+    };
+
+    let session = match state.sessions.start_turn(&id, owner.as_deref()) {
+        Ok(session) => session,
+        Err((status, message)) => return error_response(status, message),
+    };
+    let model = match state.models.get(Some(&session.model)) {
+        Some(model) => model,
+        None => {
+            state.sessions.finish_turn(&id);
+            return error_response(StatusCode::NOT_FOUND, "Session's model is not available");
+        }
+    };
+    // Invalid parameters are turned away before the turn takes a place in the queue
+    let mut sampling = match model.sampling(&input.sampling) {
+        Ok(sampling) => sampling,
+        Err(error) => {
+            state.sessions.finish_turn(&id);
+            return error_response(StatusCode::BAD_REQUEST, &error.to_string());
+        }
+    };
+    sampling.add_stop_sequences(&model.template.stop);
+    let template = Arc::clone(&model.template);
+    let mut messages = session.messages;
+    messages.push(ChatMessage {
+        role: "user".to_string(),
+        content: input.content,
+    });
+    let turn = Turn {
+        state: Arc::clone(&state),
+        session_id: id,
+        system: session.system,
+        messages,
+        template,
+        sampling,
+    };
+
+    // Take the session's turn on its model like any other request
+    spawn_on_model(
+        req,
+        state,
+        &session.model,
+        Usage::Generation,
+        move |llm_guard, tx| reply_endpoint(turn, llm_guard, tx),
+    )
+    .await
+}
+
+// Reply to the last message of a turn, and add both to the session's history
+async fn reply_endpoint(
+    turn: Turn,
+    mut llm_guard: QueueGuard,
+    tx: oneshot::Sender<Result<Response<Body>, LLMError>>,
+) {
+    let result = match turn.fit_prompt(&*llm_guard) {
+        Ok((prompt, truncated)) => llm_guard
+            .submit_prompt(&prompt, &turn.sampling)
+            .await
+            .map(|reply| (reply.trim().to_string(), truncated)),
+        Err(error) => Err(error),
+    };
+
+    // Failed messages are left out of the history, so they can simply be sent again
+    let res = match result {
+        Ok((reply, truncated)) => {
+            if let Some(message) = turn.messages.last() {
+                turn.state.sessions.append(
+                    &turn.session_id,
+                    truncated,
+                    message.clone(),
+                    ChatMessage {
+                        role: "assistant".to_string(),
+                        content: reply.clone(),
+                    },
+                );
+            }
+            json_response(
+                StatusCode::OK,
+                &MessageResponse {
+                    success: true,
+                    response: reply,
+                    truncated,
+                },
+            )
+        }
+        // A message which doesn't fit in the context window is the client's mistake, like the
+        // prompt endpoints' invalid parameters, while anything else went wrong in the model
+        Err(error @ LLMError::InvalidParameters(_)) => {
+            error_response(StatusCode::BAD_REQUEST, &error.to_string())
+        }
+        Err(error) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &error.to_string()),
+    };
+    if tx.send(res).is_err() {
+        warn!("Failed to send session response");
+    }
+}
@@ -0,0 +1,25 @@
+use crate::cancel::Generations;
+use crate::health::Health;
+use crate::jobs::JobStore;
+use crate::keyring::Keyring;
+use crate::metrics::Metrics;
+use crate::ratelimit::RateLimiter;
+use crate::registry::ModelRegistry;
+use crate::sessions::SessionStore;
+use std::sync::Arc;
+
+// State shared by every request handler.
+// Everything besides the models' queues, jobs, sessions, usage counters and metrics is immutable,
+// so reading it never waits on an LLM.
+pub struct ServerState {
+    pub models: ModelRegistry,
+    pub jobs: JobStore,
+    pub sessions: SessionStore,
+    pub generations: Generations,
+    // The keys requests must provide, if any
+    pub keyring: Option<Keyring>,
+    pub rate_limiter: RateLimiter,
+    // Shared with the model registry, which records model load and queue wait times
+    pub metrics: Arc<Metrics>,
+    pub health: Health,
+}
@@ -0,0 +1,211 @@
+use crate::fs_reading::load_toml_or_yaml;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+// The template models use unless given another one
+pub const DEFAULT_TEMPLATE: &str = "plain";
+
+// Where a message's text goes in the templates' formats
+const CONTENT: &str = "{content}";
+
+// Struct to represent a single chat message
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+// The roles a chat message may have
+pub const ROLES: &[&str] = &["system", "user", "assistant"];
+
+// Checks the messages of a chat request, before rendering them
+pub fn check_messages(messages: &[ChatMessage]) -> Result<(), String> {
+    if messages.is_empty() {
+        return Err("`messages` must not be empty".to_string());
+    }
+    for message in messages {
+        if !ROLES.contains(&message.role.as_str()) {
+            return Err(format!(
+                "Unknown message role `{}`, expected one of: {}",
+                message.role,
+                ROLES.join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Struct to represent the templates file given with `--templates_file`
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct TemplatesFile {
+    templates: BTreeMap<String, ChatTemplate>,
+}
+
+// How chat messages are rendered into the prompt a model was trained on.
+// Each message is rendered with the format of its role, where `{content}` is replaced by its text.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ChatTemplate {
+    system: String,
+    user: String,
+    assistant: String,
+    // Starts the assistant's reply, at the end of the prompt
+    reply: String,
+    // Used when the messages don't include a system message
+    default_system: Option<String>,
+    // Where the assistant's reply ends, ie. the start of the next turn
+    #[serde(default)]
+    pub stop: Vec<String>,
+}
+
+// A plain transcript, which works reasonably with any model
+impl Default for ChatTemplate {
+    fn default() -> Self {
+        Self {
+            system: "System: {content}\n".to_string(),
+            user: "User: {content}\n".to_string(),
+            assistant: "Assistant: {content}\n".to_string(),
+            reply: "Assistant:".to_string(),
+            default_system: None,
+            stop: vec!["\nUser:".to_string()],
+        }
+    }
+}
+
+impl ChatTemplate {
+    // Renders the messages into a prompt, ending with the start of the assistant's reply.
+    // Messages with a role other than `system` or `assistant` are rendered as the user's.
+    pub fn render(&self, messages: &[ChatMessage]) -> String {
+        let mut prompt = String::new();
+        if let Some(default_system) = &self.default_system {
+            if !messages.iter().any(|message| message.role == "system") {
+                prompt.push_str(&self.system.replace(CONTENT, default_system));
+            }
+        }
+        for message in messages {
+            let format = match message.role.as_str() {
+                "system" => &self.system,
+                "assistant" => &self.assistant,
+                _ => &self.user,
+            };
+            prompt.push_str(&format.replace(CONTENT, &message.content));
+        }
+        prompt.push_str(&self.reply);
+        prompt
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        for (role, format) in [
+            ("system", &self.system),
+            ("user", &self.user),
+            ("assistant", &self.assistant),
+        ] {
+            if !format.contains(CONTENT) {
+                return Err(format!("`{}` must contain `{}`", role, CONTENT));
+            }
+        }
+        if self.stop.iter().any(|stop| stop.is_empty()) {
+            return Err("stop sequences must not be empty".to_string());
+        }
+        Ok(())
+    }
+
+    // Vicuna (v1.1 and later)
+    fn vicuna() -> Self {
+        Self {
+            system: "{content}\n\n".to_string(),
+            user: "USER: {content}\n".to_string(),
+            assistant: "ASSISTANT: {content}</s>\n".to_string(),
+            reply: "ASSISTANT:".to_string(),
+            default_system: Some(
+                "A chat between a curious user and an artificial intelligence assistant. \
+                 The assistant gives helpful, detailed, and polite answers to the user's questions."
+                    .to_string(),
+            ),
+            stop: vec!["USER:".to_string()],
+        }
+    }
+
+    // Alpaca, and other instruction tuned models
+    fn alpaca() -> Self {
+        Self {
+            system: "{content}\n\n".to_string(),
+            user: "### Instruction:\n{content}\n\n".to_string(),
+            assistant: "### Response:\n{content}\n\n".to_string(),
+            reply: "### Response:\n".to_string(),
+            default_system: Some(
+                "Below is an instruction that describes a task. \
+                 Write a response that appropriately completes the request."
+                    .to_string(),
+            ),
+            stop: vec!["### Instruction:".to_string()],
+        }
+    }
+
+    // ChatML, as used by OpenHermes, Dolphin and others
+    fn chatml() -> Self {
+        Self {
+            system: "<|im_start|>system\n{content}<|im_end|>\n".to_string(),
+            user: "<|im_start|>user\n{content}<|im_end|>\n".to_string(),
+            assistant: "<|im_start|>assistant\n{content}<|im_end|>\n".to_string(),
+            reply: "<|im_start|>assistant\n".to_string(),
+            default_system: None,
+            stop: vec!["<|im_end|>".to_string()],
+        }
+    }
+}
+
+// Every template models may use, by name
+pub struct Templates {
+    templates: BTreeMap<String, Arc<ChatTemplate>>,
+}
+
+impl Templates {
+    // The templates which come with the server
+    pub fn builtin() -> Self {
+        let templates = [
+            (DEFAULT_TEMPLATE, ChatTemplate::default()),
+            ("vicuna", ChatTemplate::vicuna()),
+            ("alpaca", ChatTemplate::alpaca()),
+            ("chatml", ChatTemplate::chatml()),
+        ];
+        Self {
+            templates: templates
+                .into_iter()
+                .map(|(name, template)| (name.to_string(), Arc::new(template)))
+                .collect(),
+        }
+    }
+
+    // The built-in templates, along with those from the templates file.
+    // Templates from the file replace built-in ones of the same name.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let file: TemplatesFile = load_toml_or_yaml(path, "templates file")?;
+
+        let mut templates = Self::builtin();
+        for (name, template) in file.templates {
+            template.validate().map_err(|e| {
+                format!(
+                    "Invalid templates file {}: template `{}`: {}",
+                    path, name, e
+                )
+            })?;
+            templates.templates.insert(name, Arc::new(template));
+        }
+        Ok(templates)
+    }
+
+    // Look up a template by name, failing with the names of those which exist
+    pub fn get(&self, name: &str) -> Result<Arc<ChatTemplate>, String> {
+        self.templates.get(name).cloned().ok_or_else(|| {
+            let names: Vec<&str> = self.templates.keys().map(|name| name.as_str()).collect();
+            format!(
+                "Unknown chat template `{}`, expected one of: {}",
+                name,
+                names.join(", ")
+            )
+        })
+    }
+}
@@ -0,0 +1,1158 @@
+// End-to-end tests of the HTTP API, served on an ephemeral port by the mock backend,
+// so they need no model files
+use crate::backend::BackendKind;
+use crate::keyring::Keyring;
+use crate::mock::MockSettings;
+use crate::ratelimit::RateLimits;
+use crate::registry::{ModelSettings, ModelSpec};
+use crate::sampling::SamplingLimits;
+use crate::templates::Templates;
+use crate::{run_webserver, APP_VERSION};
+use hyper::body::to_bytes;
+use hyper::header::HeaderMap;
+use hyper::{header, Body, Client, Method, Request, StatusCode};
+use serde_json::Value;
+use std::net::{SocketAddr, TcpListener};
+use std::time::{Duration, Instant};
+
+const API_KEY: &str = "test-key";
+const OTHER_API_KEY: &str = "other-test-key";
+
+// How the server under test is set up
+struct TestOptions {
+    api_key: Option<&'static str>,
+    // A second key, which may use everything too
+    other_api_key: Option<&'static str>,
+    // How long the mock takes to respond, so tests can catch it busy
+    latency: Duration,
+    queue_depth: usize,
+    // How many tokens fit in the mock model's context window
+    context_size: usize,
+    // The models served, as `name=path`; the first is the default
+    models: Vec<String>,
+    // In bytes
+    memory_budget: Option<u64>,
+    // The limits of every client
+    rate_limits: RateLimits,
+}
+
+impl Default for TestOptions {
+    fn default() -> Self {
+        Self {
+            api_key: None,
+            other_api_key: None,
+            latency: Duration::ZERO,
+            queue_depth: 16,
+            context_size: 2048,
+            models: vec!["mock=mock.bin".to_string()],
+            memory_budget: None,
+            rate_limits: RateLimits::default(),
+        }
+    }
+}
+
+// Writes a model file of the given size, which the memory budget counts the model as using,
+// returning its path. The mock backend never reads it.
+fn model_file(name: &str, size: usize) -> String {
+    let path = std::env::temp_dir().join(format!("open-llm-server-test-{}.bin", name));
+    std::fs::write(&path, vec![0u8; size]).unwrap();
+    path.to_string_lossy().to_string()
+}
+
+// A response, with its body read
+struct TestResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: String,
+}
+
+impl TestResponse {
+    fn json(&self) -> Value {
+        serde_json::from_str(&self.body).expect("response body is JSON")
+    }
+
+    // The Server-Sent Events of the body, as their event name (if any) and data
+    fn events(&self) -> Vec<(Option<&str>, &str)> {
+        self.body
+            .split("\n\n")
+            .filter(|event| !event.is_empty())
+            .map(|event| {
+                let name = event.lines().find_map(|line| line.strip_prefix("event: "));
+                let data = event
+                    .lines()
+                    .find_map(|line| line.strip_prefix("data: "))
+                    .unwrap_or_default();
+                (name, data)
+            })
+            .collect()
+    }
+}
+
+// A server running in the background until the test's runtime shuts down
+#[derive(Clone, Copy)]
+struct TestServer {
+    addr: SocketAddr,
+    api_key: Option<&'static str>,
+}
+
+impl TestServer {
+    fn start(options: TestOptions) -> Self {
+        // Connections are queued by the listener until the server accepts them,
+        // so requests can be sent as soon as it is bound
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let settings = ModelSettings {
+            num_threads: 1,
+            temp: 0.7,
+            freq_penalty: 1.2,
+            output_tokens: 64,
+            limits: SamplingLimits {
+                max_temp: 2.0,
+                max_top_k: 100,
+                max_repeat_penalty: 2.0,
+                max_output_tokens: 128,
+                max_stop_sequences: 4,
+            },
+            log_prompts: false,
+            backend: BackendKind::Mock(MockSettings {
+                latency: options.latency,
+                context_size: options.context_size,
+                ..Default::default()
+            }),
+        };
+        let keyring = options.api_key.map(|key| {
+            let mut keyring = Keyring::new();
+            keyring.add_unrestricted("default", key).unwrap();
+            if let Some(other) = options.other_api_key {
+                keyring.add_unrestricted("other", other).unwrap();
+            }
+            keyring
+        });
+        let models: Vec<ModelSpec> = options.models.iter().map(|m| ModelSpec::parse(m)).collect();
+        tokio::spawn(async move {
+            let _ = run_webserver(
+                &models,
+                &Templates::builtin(),
+                settings,
+                listener,
+                None,
+                keyring,
+                options.queue_depth,
+                Duration::from_secs(10),
+                Duration::from_secs(60),
+                Duration::from_secs(60),
+                options.memory_budget,
+                None,
+                options.rate_limits,
+            )
+            .await;
+        });
+        Self {
+            addr,
+            api_key: options.api_key,
+        }
+    }
+
+    // Sends a request with the server's API key (if any)
+    async fn request(&self, method: Method, path: &str, body: &str) -> TestResponse {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(format!("http://{}{}", self.addr, path));
+        if let Some(key) = self.api_key {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {}", key));
+        }
+        self.send(req.body(Body::from(body.to_string())).unwrap())
+            .await
+    }
+
+    async fn get(&self, path: &str) -> TestResponse {
+        self.request(Method::GET, path, "").await
+    }
+
+    async fn post(&self, path: &str, body: &str) -> TestResponse {
+        self.request(Method::POST, path, body).await
+    }
+
+    // Sends a request as is, filling in the server's address
+    async fn send(&self, mut req: Request<Body>) -> TestResponse {
+        if req.uri().authority().is_none() {
+            *req.uri_mut() = format!("http://{}{}", self.addr, req.uri())
+                .parse()
+                .unwrap();
+        }
+        let res = Client::new().request(req).await.expect("request succeeds");
+        let (parts, body) = res.into_parts();
+        let body = to_bytes(body).await.unwrap();
+        TestResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body: String::from_utf8(body.to_vec()).unwrap(),
+        }
+    }
+
+    // Waits for a request to get hold of the default model
+    async fn wait_until_busy(&self) {
+        let started = Instant::now();
+        while !self.get("/is_busy").await.json()["is_busy"]
+            .as_bool()
+            .unwrap()
+        {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "the model never became busy"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn root_reports_the_version() {
+    let server = TestServer::start(TestOptions::default());
+    let res = server.get("/").await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.body, format!("Open LLM Server v{}", APP_VERSION));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn submit_prompt_returns_the_generated_text() {
+    let server = TestServer::start(TestOptions::default());
+    let res = server
+        .post("/submit_prompt", r#"{"prompt": "Hello there, world"}"#)
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(
+        res.json(),
+        serde_json::json!({"success": true, "response": "Hello there, world"})
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn submit_prompt_applies_sampling_parameters() {
+    let server = TestServer::start(TestOptions::default());
+    let res = server
+        .post(
+            "/submit_prompt",
+            r#"{"prompt": "one two three four", "n_tok_predict": 2}"#,
+        )
+        .await;
+    assert_eq!(res.json()["response"], "one two");
+
+    for path in ["/submit_prompt", "/submit_prompt_streaming"] {
+        let res = server
+            .post(path, r#"{"prompt": "Hello", "temp": -1}"#)
+            .await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST, "{}", path);
+        assert_eq!(res.json()["success"], false);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn submit_prompt_streaming_sends_each_token() {
+    let server = TestServer::start(TestOptions::default());
+    let res = server
+        .post("/submit_prompt_streaming", r#"{"prompt": "one two three"}"#)
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.headers[header::CONTENT_TYPE], "text/event-stream");
+    let events: Vec<(Option<&str>, Value)> = res
+        .events()
+        .into_iter()
+        .map(|(name, data)| (name, serde_json::from_str(data).unwrap()))
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            (None, serde_json::json!({"token": "one"})),
+            (None, serde_json::json!({"token": " two"})),
+            (None, serde_json::json!({"token": " three"})),
+            (
+                Some("done"),
+                serde_json::json!({"success": true, "response": "one two three"})
+            ),
+        ]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn submit_prompt_rejects_malformed_json() {
+    let server = TestServer::start(TestOptions::default());
+    for body in [r#"{"prompt": "#, "", r#"{"text": "Hello"}"#] {
+        let res = server.post("/submit_prompt", body).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST, "body: {:?}", body);
+        assert_eq!(res.json()["success"], false);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn endpoints_reject_bodies_which_are_not_utf8() {
+    let server = TestServer::start(TestOptions::default());
+    for (path, body) in [
+        ("/submit_prompt", &b"{\"prompt\": \"\xff\"}"[..]),
+        ("/submit_prompt_streaming", &b"{\"prompt\": \"\xff\"}"[..]),
+        ("/generate_embeddings", &b"{\"input\": \"\xff\"}"[..]),
+    ] {
+        let req = Request::post(path).body(Body::from(body)).unwrap();
+        let res = server.send(req).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST, "{}", path);
+        assert_eq!(res.json()["success"], false);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn generate_embeddings_returns_a_vector_per_input() {
+    let server = TestServer::start(TestOptions::default());
+    let res = server
+        .post("/generate_embeddings", r#"{"input": ["Hello", "world"]}"#)
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    let json = res.json();
+    assert_eq!(json["success"], true);
+    assert_eq!(json["embeddings"].as_array().unwrap().len(), 2);
+    assert_eq!(
+        json["embeddings"][0].as_array().unwrap().len(),
+        json["dimension"]
+    );
+
+    // Prompts still run on the model once it is also loaded in embedding mode
+    let res = server
+        .post("/submit_prompt", r#"{"prompt": "Hello"}"#)
+        .await;
+    assert_eq!(res.json()["response"], "Hello");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn embeddings_of_inputs_longer_than_the_context_are_rejected() {
+    let server = TestServer::start(TestOptions {
+        context_size: 4,
+        ..Default::default()
+    });
+    let res = server
+        .post(
+            "/generate_embeddings",
+            r#"{"input": ["Hello", "one two three four five"]}"#,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    assert_eq!(res.json()["success"], false);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn embeddings_need_room_for_a_second_copy_of_the_model() {
+    let server = TestServer::start(TestOptions {
+        models: vec![format!("big={}", model_file("big", 600))],
+        memory_budget: Some(1000),
+        ..Default::default()
+    });
+    let res = server
+        .post("/submit_prompt", r#"{"prompt": "Hello"}"#)
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+
+    // Retrying can't help, so there is no Retry-After
+    let res = server
+        .post("/generate_embeddings", r#"{"input": "Hello"}"#)
+        .await;
+    assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(res.json()["success"], false);
+    assert!(!res.headers.contains_key(header::RETRY_AFTER));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn least_recently_used_models_are_unloaded_to_make_room() {
+    let server = TestServer::start(TestOptions {
+        models: vec![
+            format!("a={}", model_file("evict-a", 600)),
+            format!("b={}", model_file("evict-b", 600)),
+        ],
+        memory_budget: Some(1000),
+        ..Default::default()
+    });
+    let loaded = |json: Value| -> Vec<bool> {
+        json["models"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|model| model["loaded"].as_bool().unwrap())
+            .collect()
+    };
+    for (model, expected) in [
+        ("a", [true, false]),
+        ("b", [false, true]),
+        ("a", [true, false]),
+    ] {
+        let body = format!(r#"{{"prompt": "Hello", "model": "{}"}}"#, model);
+        let res = server.post("/submit_prompt", &body).await;
+        assert_eq!(res.status, StatusCode::OK, "{}", res.body);
+        assert_eq!(loaded(server.get("/models").await.json()), expected);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn submit_prompt_rejects_unknown_models() {
+    let server = TestServer::start(TestOptions::default());
+    let res = server
+        .post("/submit_prompt", r#"{"prompt": "Hello", "model": "other"}"#)
+        .await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(res.json()["success"], false);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn is_busy_reports_an_idle_model() {
+    let server = TestServer::start(TestOptions::default());
+    let res = server.get("/is_busy").await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(
+        res.json(),
+        serde_json::json!({"success": true, "is_busy": false, "queued": 0})
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn requests_without_an_api_key_are_rejected() {
+    let server = TestServer::start(TestOptions {
+        api_key: Some(API_KEY),
+        ..Default::default()
+    });
+    let req = Request::post("/submit_prompt")
+        .body(Body::from(r#"{"prompt": "Hello"}"#))
+        .unwrap();
+    let res = server.send(req).await;
+    assert_eq!(res.status, StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        res.headers[header::WWW_AUTHENTICATE],
+        "Bearer realm=\"open-llm-server\""
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn requests_with_a_wrong_api_key_are_rejected() {
+    let server = TestServer::start(TestOptions {
+        api_key: Some(API_KEY),
+        ..Default::default()
+    });
+    for key in [
+        "Bearer wrong-key",
+        "wrong-key",
+        "Bearer ",
+        "Bearer test-key-2",
+    ] {
+        let req = Request::get("/is_busy")
+            .header(header::AUTHORIZATION, key)
+            .body(Body::empty())
+            .unwrap();
+        let res = server.send(req).await;
+        assert_eq!(res.status, StatusCode::UNAUTHORIZED, "key: {:?}", key);
+        assert!(res.headers[header::WWW_AUTHENTICATE]
+            .to_str()
+            .unwrap()
+            .contains("error=\"invalid_token\""));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn requests_with_the_api_key_are_accepted() {
+    let server = TestServer::start(TestOptions {
+        api_key: Some(API_KEY),
+        ..Default::default()
+    });
+    let res = server
+        .post("/submit_prompt", r#"{"prompt": "Hello"}"#)
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.json()["response"], "Hello");
+
+    // The scheme is optional, and case insensitive
+    for key in [API_KEY.to_string(), format!("bearer {}", API_KEY)] {
+        let req = Request::get("/is_busy")
+            .header(header::AUTHORIZATION, key)
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(server.send(req).await.status, StatusCode::OK);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn health_checks_need_no_api_key() {
+    let server = TestServer::start(TestOptions {
+        api_key: Some(API_KEY),
+        ..Default::default()
+    });
+    for path in ["/health/live", "/health/ready"] {
+        let req = Request::get(path).body(Body::empty()).unwrap();
+        assert_eq!(server.send(req).await.status, StatusCode::OK, "{}", path);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn health_checks_never_load_the_model() {
+    let server = TestServer::start(TestOptions::default());
+    let res = server.get("/health/ready?generate=true").await;
+    assert_eq!(res.status, StatusCode::OK, "{}", res.body);
+    let body = res.json();
+    assert_eq!(body["generation"], Value::Null);
+    assert_eq!(body["models"][0]["loaded"], false);
+
+    // Once a request has loaded it, the model gets a test generation
+    server.post("/submit_prompt", r#"{"prompt": "Hi"}"#).await;
+    let body = server.get("/health/ready?generate=true").await.json();
+    assert_eq!(body["generation"]["success"], true, "{}", body);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn health_checks_keep_the_cached_prompt() {
+    let server = TestServer::start(TestOptions::default());
+    server
+        .post("/submit_prompt", r#"{"prompt": "You are helpful. Hi"}"#)
+        .await;
+    let body = server.get("/health/ready?generate=true").await.json();
+    assert_eq!(body["generation"]["success"], true, "{}", body);
+    server
+        .post("/submit_prompt", r#"{"prompt": "You are helpful. Bye"}"#)
+        .await;
+    // Generations are counted right after their response is sent
+    let started = Instant::now();
+    let metrics = loop {
+        let metrics = server.get("/metrics").await.body;
+        if metrics.contains("open_llm_prompt_tokens_total 8\n")
+            || started.elapsed() > Duration::from_secs(5)
+        {
+            break metrics;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    assert!(
+        metrics.contains("open_llm_prompt_tokens_cached_total 3\n"),
+        "{}",
+        metrics
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reloads_from_another_file_need_the_admin_scope() {
+    // Without API keys, models may only be reloaded from their current files
+    let server = TestServer::start(TestOptions::default());
+    let res = server.post("/admin/reload", "").await;
+    assert_eq!(res.status, StatusCode::OK, "{}", res.body);
+    let res = server
+        .post("/admin/reload", r#"{"path": "Cargo.toml"}"#)
+        .await;
+    assert_eq!(res.status, StatusCode::FORBIDDEN, "{}", res.body);
+
+    // The key given with `--api-key` has every scope
+    let server = TestServer::start(TestOptions {
+        api_key: Some(API_KEY),
+        ..Default::default()
+    });
+    let res = server
+        .post("/admin/reload", r#"{"path": "Cargo.toml"}"#)
+        .await;
+    assert_eq!(res.status, StatusCode::OK, "{}", res.body);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn is_busy_reports_a_model_in_use() {
+    let server = TestServer::start(TestOptions {
+        latency: Duration::from_millis(500),
+        ..Default::default()
+    });
+    let generation = tokio::spawn(async move {
+        server
+            .post("/submit_prompt", r#"{"prompt": "Hello"}"#)
+            .await
+    });
+    server.wait_until_busy().await;
+
+    // Requests which don't need the model are still answered right away
+    assert_eq!(server.get("/").await.status, StatusCode::OK);
+
+    let res = generation.await.unwrap();
+    assert_eq!(res.json()["response"], "Hello");
+    assert_eq!(server.get("/is_busy").await.json()["is_busy"], false);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn requests_queue_for_a_busy_model() {
+    let server = TestServer::start(TestOptions {
+        latency: Duration::from_millis(300),
+        ..Default::default()
+    });
+    let prompts = ["first", "second", "third"];
+    let started = Instant::now();
+    let generations: Vec<_> = prompts
+        .iter()
+        .map(|prompt| {
+            let body = format!(r#"{{"prompt": "{}"}}"#, prompt);
+            tokio::spawn(async move { server.post("/submit_prompt", &body).await })
+        })
+        .collect();
+    for (generation, prompt) in generations.into_iter().zip(prompts) {
+        let res = generation.await.unwrap();
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.json()["response"], prompt);
+    }
+    // The model is used by one request at a time
+    assert!(started.elapsed() >= Duration::from_millis(900));
+}
+
+// Builds a request made with the given API key and request id
+fn request_with_id(path: &str, body: &str, key: &str, request_id: &str) -> Request<Body> {
+    Request::post(path)
+        .header(header::AUTHORIZATION, format!("Bearer {}", key))
+        .header("X-Request-Id", request_id)
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn generations_are_only_cancelled_by_their_key() {
+    let server = TestServer::start(TestOptions {
+        api_key: Some(API_KEY),
+        other_api_key: Some(OTHER_API_KEY),
+        latency: Duration::from_millis(500),
+        ..Default::default()
+    });
+    let prompt = r#"{"prompt": "one two three"}"#;
+    let generation = tokio::spawn(async move {
+        let req = request_with_id("/submit_prompt", prompt, API_KEY, "my-request");
+        server.send(req).await
+    });
+    server.wait_until_busy().await;
+
+    // The request id can't be reused while its generation is in flight
+    let req = request_with_id("/submit_prompt", prompt, OTHER_API_KEY, "my-request");
+    assert_eq!(server.send(req).await.status, StatusCode::CONFLICT);
+
+    // Only the key which started the generation may cancel it
+    let req = request_with_id("/cancel/my-request", "", OTHER_API_KEY, "cancel-1");
+    assert_eq!(server.send(req).await.status, StatusCode::NOT_FOUND);
+    let req = request_with_id("/cancel/my-request", "", API_KEY, "cancel-2");
+    assert_eq!(server.send(req).await.status, StatusCode::OK);
+
+    let res = generation.await.unwrap();
+    assert_eq!(res.json()["success"], false);
+    assert_eq!(res.json()["response"], "The generation was cancelled.");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cancelled_generations_release_the_model() {
+    let server = TestServer::start(TestOptions {
+        latency: Duration::from_millis(500),
+        ..Default::default()
+    });
+    let generation = tokio::spawn(async move {
+        let req = Request::post("/submit_prompt_streaming")
+            .header("x-request-id", "slow")
+            .body(Body::from(r#"{"prompt": "one two three"}"#))
+            .unwrap();
+        server.send(req).await
+    });
+    server.wait_until_busy().await;
+    let res = server.post("/cancel/slow", "").await;
+    assert_eq!(res.status, StatusCode::OK, "{}", res.body);
+
+    // The stream ends with an error event, and the model takes the next request
+    let res = generation.await.unwrap();
+    let (name, data) = *res.events().last().unwrap();
+    assert_eq!(name, Some("error"));
+    assert_eq!(
+        serde_json::from_str::<Value>(data).unwrap()["response"],
+        "The generation was cancelled."
+    );
+    let res = server
+        .post("/submit_prompt", r#"{"prompt": "Hello"}"#)
+        .await;
+    assert_eq!(res.json()["response"], "Hello");
+
+    // Only generations in flight can be cancelled
+    let res = server.post("/cancel/slow", "").await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn jobs_run_in_the_background() {
+    let server = TestServer::start(TestOptions::default());
+    let res = server.post("/jobs", r#"{"prompt": "one two three"}"#).await;
+    assert_eq!(res.status, StatusCode::ACCEPTED, "{}", res.body);
+    let job = format!("/jobs/{}", res.json()["job_id"].as_str().unwrap());
+
+    let started = Instant::now();
+    let body = loop {
+        let body = server.get(&job).await.json();
+        if body["status"] == "done" || started.elapsed() > Duration::from_secs(5) {
+            break body;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    assert_eq!(body["status"], "done", "{}", body);
+    assert_eq!(body["output"], "one two three");
+    assert_eq!(body["response"], "one two three");
+
+    // Finished jobs can't be cancelled anymore
+    let req = Request::delete(job).body(Body::empty()).unwrap();
+    assert_eq!(server.send(req).await.status, StatusCode::CONFLICT);
+    let res = server.get("/jobs/unknown").await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cancelled_jobs_release_the_model() {
+    let server = TestServer::start(TestOptions {
+        latency: Duration::from_millis(500),
+        ..Default::default()
+    });
+    let res = server.post("/jobs", r#"{"prompt": "one two three"}"#).await;
+    let job = format!("/jobs/{}", res.json()["job_id"].as_str().unwrap());
+    server.wait_until_busy().await;
+
+    let req = Request::delete(&job).body(Body::empty()).unwrap();
+    let res = server.send(req).await;
+    assert_eq!(res.status, StatusCode::OK, "{}", res.body);
+    assert_eq!(res.json()["status"], "cancelled");
+    assert_eq!(server.get(&job).await.json()["status"], "cancelled");
+    let res = server
+        .post("/submit_prompt", r#"{"prompt": "Hello"}"#)
+        .await;
+    assert_eq!(res.json()["response"], "Hello");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rate_limits_report_the_budget_left() {
+    let server = TestServer::start(TestOptions {
+        rate_limits: RateLimits {
+            requests_per_minute: Some(2),
+            tokens_per_day: Some(100),
+        },
+        ..Default::default()
+    });
+    for remaining in ["1", "0"] {
+        let res = server
+            .post("/submit_prompt", r#"{"prompt": "Hello"}"#)
+            .await;
+        assert_eq!(res.status, StatusCode::OK);
+        assert_eq!(res.headers["x-ratelimit-limit-requests"], "2");
+        assert_eq!(res.headers["x-ratelimit-remaining-requests"], remaining);
+        assert!(res.headers.contains_key("x-ratelimit-reset-requests"));
+        assert_eq!(res.headers["x-ratelimit-limit-tokens"], "100");
+        assert!(res.headers.contains_key("x-ratelimit-remaining-tokens"));
+        assert!(res.headers.contains_key("x-ratelimit-reset-tokens"));
+    }
+
+    let res = server
+        .post("/submit_prompt", r#"{"prompt": "Hello"}"#)
+        .await;
+    assert_eq!(res.status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(res.json()["success"], false);
+    assert!(res.headers.contains_key(header::RETRY_AFTER));
+    assert_eq!(res.headers["x-ratelimit-remaining-requests"], "0");
+    // OpenAI clients get their own error format
+    let res = server
+        .post("/v1/completions", r#"{"prompt": "Hello"}"#)
+        .await;
+    assert_eq!(res.status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(res.json()["error"]["type"], "invalid_request_error");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn token_quotas_turn_requests_away_once_used_up() {
+    let server = TestServer::start(TestOptions {
+        rate_limits: RateLimits {
+            requests_per_minute: None,
+            tokens_per_day: Some(2),
+        },
+        ..Default::default()
+    });
+    // The quota is checked at the start of a request, so this one may go over it
+    let res = server
+        .post("/submit_prompt", r#"{"prompt": "one two three"}"#)
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.headers["x-ratelimit-remaining-tokens"], "2");
+
+    // Generated tokens are counted right after their response is sent
+    let started = Instant::now();
+    let res = loop {
+        let res = server
+            .post("/submit_prompt", r#"{"prompt": "Hello"}"#)
+            .await;
+        if res.status != StatusCode::OK || started.elapsed() > Duration::from_secs(5) {
+            break res;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    assert_eq!(res.status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(res.headers["x-ratelimit-remaining-tokens"], "0");
+    assert!(res.headers.contains_key(header::RETRY_AFTER));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn full_queue_turns_requests_away() {
+    let server = TestServer::start(TestOptions {
+        latency: Duration::from_millis(500),
+        queue_depth: 0,
+        ..Default::default()
+    });
+    let generation = tokio::spawn(async move {
+        server
+            .post("/submit_prompt", r#"{"prompt": "Hello"}"#)
+            .await
+    });
+    server.wait_until_busy().await;
+
+    let res = server
+        .post("/submit_prompt", r#"{"prompt": "Hello again"}"#)
+        .await;
+    assert_eq!(res.status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(res.json()["success"], false);
+    assert!(res.headers.contains_key(header::RETRY_AFTER));
+
+    assert_eq!(generation.await.unwrap().status, StatusCode::OK);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn invalid_requests_are_rejected_without_queueing() {
+    let server = TestServer::start(TestOptions {
+        latency: Duration::from_millis(500),
+        queue_depth: 0,
+        ..Default::default()
+    });
+    let generation = tokio::spawn(async move {
+        server
+            .post("/submit_prompt", r#"{"prompt": "Hello"}"#)
+            .await
+    });
+    server.wait_until_busy().await;
+
+    // The queue is full, yet these are turned away for what they are rather than for the queue
+    for (path, body) in [
+        ("/submit_prompt", r#"{"prompt": "Hello", "temp": -1}"#),
+        ("/submit_prompt", r#"{"prompt": "#),
+        ("/chat", r#"{"messages": []}"#),
+        ("/v1/completions", r#"{"prompt": "Hello", "top_p": 2}"#),
+        ("/generate_embeddings", r#"{"input": []}"#),
+    ] {
+        let res = server.post(path, body).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST, "{} {}", path, body);
+    }
+
+    assert_eq!(generation.await.unwrap().status, StatusCode::OK);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn openai_endpoints_respond_like_openai() {
+    let server = TestServer::start(TestOptions::default());
+    let res = server
+        .post("/v1/completions", r#"{"prompt": "one two three"}"#)
+        .await;
+    assert_eq!(res.status, StatusCode::OK, "{}", res.body);
+    let json = res.json();
+    assert_eq!(json["object"], "text_completion");
+    assert_eq!(json["model"], "mock");
+    assert_eq!(json["choices"][0]["text"], "one two three");
+    assert_eq!(json["choices"][0]["finish_reason"], "stop");
+    assert_eq!(
+        json["usage"],
+        serde_json::json!({"prompt_tokens": 3, "completion_tokens": 3, "total_tokens": 6})
+    );
+
+    // Hitting the token limit is reported as such
+    let res = server
+        .post(
+            "/v1/completions",
+            r#"{"prompt": "one two three", "max_tokens": 2}"#,
+        )
+        .await;
+    assert_eq!(res.json()["choices"][0]["text"], "one two");
+    assert_eq!(res.json()["choices"][0]["finish_reason"], "length");
+
+    let res = server
+        .post(
+            "/v1/chat/completions",
+            r#"{"messages": [{"role": "user", "content": "Hi"}]}"#,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK, "{}", res.body);
+    let json = res.json();
+    assert_eq!(json["object"], "chat.completion");
+    assert_eq!(json["choices"][0]["message"]["role"], "assistant");
+    assert_eq!(
+        json["choices"][0]["message"]["content"],
+        "User: Hi\nAssistant:"
+    );
+
+    let json = server.get("/v1/models").await.json();
+    assert_eq!(json["object"], "list");
+    assert_eq!(json["data"][0]["id"], "mock");
+    assert_eq!(json["data"][0]["object"], "model");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn openai_streams_end_with_done() {
+    let server = TestServer::start(TestOptions::default());
+    let res = server
+        .post(
+            "/v1/completions",
+            r#"{"prompt": "one two three", "stream": true}"#,
+        )
+        .await;
+    assert_eq!(res.headers[header::CONTENT_TYPE], "text/event-stream");
+    let events = res.events();
+    assert_eq!(events.last().unwrap().1, "[DONE]");
+    let chunks: Vec<Value> = events[..events.len() - 1]
+        .iter()
+        .map(|(_, data)| serde_json::from_str(data).unwrap())
+        .collect();
+    let text: String = chunks
+        .iter()
+        .map(|chunk| chunk["choices"][0]["text"].as_str().unwrap())
+        .collect();
+    assert_eq!(text, "one two three");
+    assert_eq!(
+        chunks.last().unwrap()["choices"][0]["finish_reason"],
+        "stop"
+    );
+
+    let res = server
+        .post(
+            "/v1/chat/completions",
+            r#"{"messages": [{"role": "user", "content": "Hi"}], "stream": true}"#,
+        )
+        .await;
+    let events = res.events();
+    assert_eq!(events.last().unwrap().1, "[DONE]");
+    let chunks: Vec<Value> = events[..events.len() - 1]
+        .iter()
+        .map(|(_, data)| serde_json::from_str(data).unwrap())
+        .collect();
+    assert!(chunks
+        .iter()
+        .all(|chunk| chunk["object"] == "chat.completion.chunk"));
+    // The first chunk names the role, and the rest carry the content
+    assert_eq!(chunks[0]["choices"][0]["delta"]["role"], "assistant");
+    let content: String = chunks
+        .iter()
+        .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str())
+        .collect();
+    assert_eq!(content, "User: Hi\nAssistant:");
+    assert_eq!(
+        chunks.last().unwrap()["choices"][0]["finish_reason"],
+        "stop"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn prompts_longer_than_the_context_are_rejected() {
+    let server = TestServer::start(TestOptions {
+        context_size: 4,
+        ..Default::default()
+    });
+    let prompt = r#"{"prompt": "one two three four five"}"#;
+    let res = server.post("/submit_prompt", prompt).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    assert_eq!(res.json()["success"], false);
+
+    let res = server.post("/v1/completions", prompt).await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    assert_eq!(res.json()["error"]["type"], "invalid_request_error");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn chat_renders_messages_with_the_models_template() {
+    let server = TestServer::start(TestOptions::default());
+    let res = server
+        .post(
+            "/chat",
+            r#"{"messages": [{"role": "user", "content": "Hi"}]}"#,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(
+        res.json(),
+        serde_json::json!({"success": true, "response": "User: Hi\nAssistant:"})
+    );
+
+    // The mock echoes the whole conversation, which stops at the template's next user turn
+    let res = server
+        .post(
+            "/chat",
+            r#"{"messages": [{"role": "system", "content": "Be brief."}, {"role": "user", "content": "Hi"}]}"#,
+        )
+        .await;
+    assert_eq!(res.json()["response"], "System: Be brief.");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn chat_rejects_invalid_messages() {
+    let server = TestServer::start(TestOptions::default());
+    for body in [
+        r#"{"messages": []}"#,
+        r#"{"messages": [{"role": "narrator", "content": "Hi"}]}"#,
+        r#"{"messages": "Hi"}"#,
+    ] {
+        let res = server.post("/chat", body).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST, "body: {:?}", body);
+        assert_eq!(res.json()["success"], false);
+
+        let res = server.post("/v1/chat/completions", body).await;
+        assert_eq!(res.status, StatusCode::BAD_REQUEST, "body: {:?}", body);
+        assert_eq!(res.json()["error"]["type"], "invalid_request_error");
+    }
+}
+
+// Creates a session on the test server, returning its id
+async fn create_session(server: &TestServer, body: &str) -> String {
+    let res = server.post("/sessions", body).await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    res.json()["session_id"].as_str().unwrap().to_string()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sessions_keep_the_conversation_history() {
+    let server = TestServer::start(TestOptions::default());
+    let id = create_session(&server, r#"{"system": "Be brief."}"#).await;
+    let messages = format!("/sessions/{}/messages", id);
+
+    // The mock echoes the prompt, up to the start of the next user turn
+    let res = server.post(&messages, r#"{"content": "Hi"}"#).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(
+        res.json(),
+        serde_json::json!({"success": true, "response": "System: Be brief.", "truncated": 0})
+    );
+    let res = server.post(&messages, r#"{"content": "Bye"}"#).await;
+    assert_eq!(res.json()["response"], "System: Be brief.");
+
+    let res = server.get(&format!("/sessions/{}", id)).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(
+        res.json(),
+        serde_json::json!({
+            "success": true,
+            "session_id": id,
+            "model": "mock",
+            "system": "Be brief.",
+            "messages": [
+                {"role": "user", "content": "Hi"},
+                {"role": "assistant", "content": "System: Be brief."},
+                {"role": "user", "content": "Bye"},
+                {"role": "assistant", "content": "System: Be brief."},
+            ],
+        })
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sessions_leave_out_messages_which_no_longer_fit() {
+    let server = TestServer::start(TestOptions {
+        context_size: 16,
+        ..Default::default()
+    });
+    let id = create_session(&server, "").await;
+    let messages = format!("/sessions/{}/messages", id);
+
+    // With 4 tokens kept for the reply, prompts may take 12 of the 16
+    let res = server
+        .post(
+            &messages,
+            r#"{"content": "one two three", "n_tok_predict": 4}"#,
+        )
+        .await;
+    assert_eq!(res.json()["response"], "User: one two three");
+    assert_eq!(res.json()["truncated"], 0);
+
+    // The whole conversation would take 14 tokens, so the first turn is left out, and dropped
+    // from the history
+    let res = server
+        .post(
+            &messages,
+            r#"{"content": "four five six", "n_tok_predict": 4}"#,
+        )
+        .await;
+    assert_eq!(res.json()["response"], "User: four five six");
+    assert_eq!(res.json()["truncated"], 2);
+
+    // Messages which don't fit on their own fail, and are left out of the history
+    let long = vec!["word"; 20].join(" ");
+    let res = server
+        .post(&messages, &format!(r#"{{"content": "{}"}}"#, long))
+        .await;
+    assert_eq!(res.status, StatusCode::BAD_REQUEST);
+    assert_eq!(res.json()["success"], false);
+    let res = server.get(&format!("/sessions/{}", id)).await;
+    let history = res.json()["messages"].as_array().unwrap().clone();
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[0]["content"], "four five six");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn deleted_sessions_are_gone() {
+    let server = TestServer::start(TestOptions::default());
+    let id = create_session(&server, "").await;
+    let req = Request::delete(format!("/sessions/{}", id))
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(server.send(req).await.status, StatusCode::OK);
+
+    let res = server.get(&format!("/sessions/{}", id)).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    let res = server
+        .post(
+            &format!("/sessions/{}/messages", id),
+            r#"{"content": "Hi"}"#,
+        )
+        .await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn prompts_reuse_the_cached_prefix_of_the_last_one() {
+    let server = TestServer::start(TestOptions::default());
+    for prompt in ["You are helpful. Hi", "You are helpful. Bye"] {
+        let body = format!(r#"{{"prompt": "{}"}}"#, prompt);
+        assert_eq!(
+            server.post("/submit_prompt", &body).await.json()["response"],
+            prompt
+        );
+    }
+    // Generations are counted right after their response is sent
+    let started = Instant::now();
+    let metrics = loop {
+        let metrics = server.get("/metrics").await.body;
+        if metrics.contains("open_llm_prompt_tokens_total 8\n")
+            || started.elapsed() > Duration::from_secs(5)
+        {
+            break metrics;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    assert!(
+        metrics.contains("open_llm_prompt_tokens_total 8\n"),
+        "{}",
+        metrics
+    );
+    assert!(
+        metrics.contains("open_llm_prompt_tokens_cached_total 3\n"),
+        "{}",
+        metrics
+    );
+}
+
+#[test]
+fn api_key_flags_beat_environment_variables() {
+    let key_file = std::env::temp_dir().join("open-llm-server-test-api-key");
+    std::fs::write(&key_file, "key-from-file\n").unwrap();
+    // No other test reads this variable
+    std::env::set_var("OPEN_LLM_API_KEY", "key-from-env");
+    let matches = crate::cli::cli_app().get_matches_from([
+        "open-llm-server",
+        "run",
+        "--api-key-file",
+        key_file.to_str().unwrap(),
+    ]);
+    let (_, sub_m) = matches.subcommand().unwrap();
+    let config = crate::config::ConfigFile::default();
+    let api_key = crate::resolve_api_key(sub_m, &config);
+    std::env::remove_var("OPEN_LLM_API_KEY");
+    assert_eq!(api_key.as_deref(), Some("key-from-file"));
+}
@@ -0,0 +1,84 @@
+use crate::logging::logging_layer;
+use crate::state::ServerState;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::net;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, warn};
+
+// Loads the PEM encoded certificate chain and private key used to terminate TLS
+pub fn load_tls_acceptor(cert_path: &str, key_path: &str) -> Result<TlsAcceptor, String> {
+    let cert_file =
+        File::open(cert_path).map_err(|e| format!("Failed to open {}: {}", cert_path, e))?;
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+        .map_err(|e| format!("Failed to read certificates from {}: {}", cert_path, e))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", cert_path));
+    }
+
+    let key_file =
+        File::open(key_path).map_err(|e| format!("Failed to open {}: {}", key_path, e))?;
+    let key = rustls_pemfile::read_all(&mut BufReader::new(key_file))
+        .map_err(|e| format!("Failed to read private key from {}: {}", key_path, e))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| format!("No private key found in {}", key_path))?;
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Invalid certificate or private key: {}", e))?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Serves the endpoints over HTTPS, doing each TLS handshake in the connection's own task
+// so a slow client can't hold up accepting the others
+pub async fn serve_tls(
+    listener: net::TcpListener,
+    acceptor: TlsAcceptor,
+    state: Arc<ServerState>,
+) -> Result<(), Box<dyn Error>> {
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    loop {
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!(error = %e, "Failed to accept connection");
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!(client = %remote_addr, error = %e, "TLS handshake failed");
+                    return;
+                }
+            };
+            let service =
+                service_fn(move |req| logging_layer(req, Arc::clone(&state), remote_addr));
+            if let Err(e) = Http::new().serve_connection(stream, service).await {
+                debug!(client = %remote_addr, error = %e, "Connection error");
+            }
+        });
+    }
+}